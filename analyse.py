@@ -6,16 +6,19 @@ import json
 with open('target/sections.json') as fp:
     data = json.load(fp)
 
-def bin_color(color_node):
+def bin_color(yarn_name):
     sumlist = []
-    for _name, section in data.items():
-        c = collections.Counter(section[color_node])
-        v = [e for _, e in c.most_common()]
-        sumlist[0:0] = v
+    for window in data['windows']:
+        for yarn in window['yarns']:
+            if yarn['yarn'] != yarn_name:
+                continue
+            c = collections.Counter(yarn['steps'])
+            v = [e for _, e in c.most_common()]
+            sumlist[0:0] = v
     return sumlist
 
-for color_node in ['nodes_gray', 'nodes_red', 'nodes_green', 'nodes_blue']:
-    sumlist = bin_color(color_node)
+for yarn_name in ['gray', 'red', 'green', 'blue']:
+    sumlist = bin_color(yarn_name)
     if not sum(sumlist):
         continue
 
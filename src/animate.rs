@@ -0,0 +1,130 @@
+//! Replay the yarn being laid, as an animation.
+use std::path::Path;
+
+use image::codecs::gif::{GifEncoder, Repeat};
+
+use crate::color::PrimaryBase;
+use crate::plan::RgbSequence;
+use crate::poly::Polygons;
+use crate::render::Canvas;
+use crate::wrap;
+
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum Format {
+    /// A single animated GIF.
+    #[default]
+    Gif,
+    /// Numbered PNG frames in a directory.
+    Png,
+}
+
+pub struct Animation {
+    pub format: Format,
+    /// How many threads to lay between two frames.
+    pub segments_per_frame: usize,
+    /// The display time of each frame.
+    pub delay_ms: u32,
+}
+
+enum Frames {
+    Gif(GifEncoder<std::fs::File>),
+    Png {
+        dir: std::path::PathBuf,
+        count: usize,
+    },
+}
+
+/// Lay the yarn window by window, in the order each window is planned.
+///
+/// A frame is emitted every few segments, at the end of each window, and for the finished board.
+pub fn animate(
+    path: &Path,
+    dimensions: (u32, u32),
+    plan: &Polygons,
+    sequences: &[RgbSequence],
+    primary: &PrimaryBase,
+    is_rgbish: bool,
+    animation: &Animation,
+) -> Result<(), eyre::Report> {
+    let mut frames = match animation.format {
+        Format::Gif => {
+            let mut encoder = GifEncoder::new_with_speed(std::fs::File::create(path)?, 10);
+            encoder.set_repeat(Repeat::Infinite)?;
+            Frames::Gif(encoder)
+        }
+        Format::Png => {
+            std::fs::create_dir_all(path)?;
+            Frames::Png {
+                dir: path.to_owned(),
+                count: 0,
+            }
+        }
+    };
+
+    let per_frame = animation.segments_per_frame.max(1);
+    let mut canvas = Canvas::new(dimensions, primary);
+    let mut pending = 0;
+
+    frames.push(&canvas, plan, animation)?;
+
+    for (window, rgb) in plan.windows.iter().zip(sequences) {
+        let mut yarns = vec![];
+
+        if is_rgbish {
+            yarns.push((&rgb.r, primary.red));
+            yarns.push((&rgb.g, primary.green));
+            yarns.push((&rgb.b, primary.blue));
+        }
+
+        yarns.push((&rgb.black, primary.gray));
+
+        for (seq, yarn) in yarns {
+            let path = wrap::on_board(&plan.board, &window.points, seq);
+
+            for &segment in &path.segments {
+                canvas.segment(&plan.board, segment, yarn);
+                pending += 1;
+
+                if pending >= per_frame {
+                    frames.push(&canvas, plan, animation)?;
+                    pending = 0;
+                }
+            }
+        }
+
+        if pending > 0 {
+            frames.push(&canvas, plan, animation)?;
+            pending = 0;
+        }
+    }
+
+    Ok(())
+}
+
+impl Frames {
+    fn push(
+        &mut self,
+        canvas: &Canvas,
+        plan: &Polygons,
+        animation: &Animation,
+    ) -> Result<(), eyre::Report> {
+        // The nails are always visible, so draw them on a copy.
+        let mut frame = canvas.clone();
+        frame.nails(plan);
+        let frame = frame.to_rgb8();
+
+        match self {
+            Frames::Gif(encoder) => {
+                let rgba = image::DynamicImage::ImageRgb8(frame).into_rgba8();
+                let delay = image::Delay::from_numer_denom_ms(animation.delay_ms, 1);
+                encoder.encode_frame(image::Frame::from_parts(rgba, 0, 0, delay))?;
+            }
+            Frames::Png { dir, count } => {
+                frame.save(dir.join(format!("{count:05}.png")))?;
+                *count += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
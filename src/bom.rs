@@ -0,0 +1,164 @@
+//! A bill of materials, everything to buy and prepare before building a plan.
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::color::PrimaryBase;
+use crate::output::css_color;
+use crate::plan::{PolygonPoint, RgbSequence, Sequence};
+use crate::poly::Polygons;
+
+pub struct Materials {
+    /// Extra yarn used up by wrapping each nail once, in millimetres.
+    pub wrap_allowance_mm: f32,
+    /// Length of yarn on one spool, in metres.
+    pub spool_length_m: f32,
+    /// Time to lay one segment between two nails, in seconds.
+    pub segment_seconds: f32,
+}
+
+#[derive(Default)]
+struct Usage {
+    length_mm: f32,
+    wraps: usize,
+    segments: usize,
+    starts: usize,
+    ends: usize,
+}
+
+pub fn write(
+    mut into: impl Write,
+    plan: &Polygons,
+    sequences: &[RgbSequence],
+    primary: &PrimaryBase,
+    is_rgbish: bool,
+    materials: &Materials,
+) -> Result<(), eyre::Report> {
+    let mut yarns = vec![];
+    if is_rgbish {
+        yarns.push(("red", primary.red, Usage::default()));
+        yarns.push(("green", primary.green, Usage::default()));
+        yarns.push(("blue", primary.blue, Usage::default()));
+    }
+
+    yarns.push(("gray", primary.gray, Usage::default()));
+
+    let nails = plan.nails();
+    let mut wraps = vec![0u32; nails.points.len()];
+
+    for (window, rgb) in sequences.iter().enumerate() {
+        let mut laid: Vec<&Sequence> = vec![];
+        if is_rgbish {
+            laid.extend([&rgb.r, &rgb.g, &rgb.b]);
+        }
+
+        laid.push(&rgb.black);
+
+        for ((_, _, usage), seq) in yarns.iter_mut().zip(laid) {
+            if seq.sequence.len() < 2 {
+                continue;
+            }
+
+            // Yarn continuing from another window shares its first wrap with that window.
+            let wrapped = &seq.sequence[usize::from(seq.linked_from.is_some())..];
+            for &PolygonPoint(point) in wrapped {
+                wraps[nails.of_window[window][point]] += 1;
+            }
+
+            usage.length_mm += seq.yarn_length;
+            usage.wraps += wrapped.len();
+            usage.segments += seq.sequence.len() - 1;
+            usage.starts += usize::from(seq.linked_from.is_none()) + seq.breaks.len();
+            usage.ends += usize::from(seq.linked_to.is_none()) + seq.breaks.len();
+        }
+    }
+
+    writeln!(into, "Bill of materials")?;
+    writeln!(into, "Board: {} x {} mm, {}", plan.board.width_mm, plan.board.height_mm, css_color(&primary.board))?;
+    writeln!(into)?;
+
+    writeln!(
+        into,
+        "Yarns, with {} mm per wrap and spools of {} m:",
+        materials.wrap_allowance_mm,
+        materials.spool_length_m,
+    )?;
+    writeln!(into, "  {:<6} {:<8} {:>10} {:>7} {:>7} {:>7} {:>9}", "yarn", "color", "metres", "spools", "starts", "ends", "segments")?;
+
+    let mut total = Usage::default();
+    for (name, color, usage) in &yarns {
+        let metres = (usage.length_mm + usage.wraps as f32 * materials.wrap_allowance_mm) / 1000.0;
+        let spools = if materials.spool_length_m > 0.0 {
+            (metres / materials.spool_length_m).ceil()
+        } else {
+            0.0
+        };
+
+        writeln!(
+            into,
+            "  {:<6} {:<8} {:>10.2} {:>7} {:>7} {:>7} {:>9}",
+            name,
+            css_color(color),
+            metres,
+            spools,
+            usage.starts,
+            usage.ends,
+            usage.segments,
+        )?;
+
+        total.length_mm += usage.length_mm;
+        total.wraps += usage.wraps;
+        total.segments += usage.segments;
+        total.starts += usage.starts;
+        total.ends += usage.ends;
+    }
+
+    let metres = (total.length_mm + total.wraps as f32 * materials.wrap_allowance_mm) / 1000.0;
+    writeln!(
+        into,
+        "  {:<6} {:<8} {:>10.2} {:>7} {:>7} {:>7} {:>9}",
+        "total", "", metres, "", total.starts, total.ends, total.segments,
+    )?;
+    writeln!(into)?;
+
+    let mut by_ring = BTreeMap::<usize, usize>::new();
+    for &ring in &nails.ring {
+        *by_ring.entry(ring).or_default() += 1;
+    }
+
+    writeln!(into, "Nails: {}", nails.points.len())?;
+    for (ring, count) in by_ring {
+        writeln!(into, "  ring {ring}: {count}")?;
+    }
+
+    for (idx, window) in plan.windows.iter().enumerate() {
+        if window.spanning {
+            writeln!(into, "  window {idx} (spanning): all")?;
+        } else {
+            writeln!(into, "  window {idx} (ring {}): {}", window.ring, window.points.len())?;
+        }
+    }
+
+    writeln!(into)?;
+    writeln!(into, "Wraps per nail, as numbered on the template:")?;
+    for (idx, (count, capacity)) in wraps.iter().zip(&nails.capacity).enumerate() {
+        match capacity {
+            Some(capacity) => writeln!(into, "  nail {idx}: {count} of {capacity}")?,
+            None => writeln!(into, "  nail {idx}: {count}")?,
+        }
+    }
+
+    writeln!(into)?;
+    writeln!(into, "Knots: {}", total.starts + total.ends)?;
+
+    let seconds = total.segments as f32 * materials.segment_seconds;
+    let minutes = (seconds / 60.0).round() as u64;
+    writeln!(
+        into,
+        "Build time: {} h {:02} min, at {} s per segment",
+        minutes / 60,
+        minutes % 60,
+        materials.segment_seconds,
+    )?;
+
+    Ok(())
+}
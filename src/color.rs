@@ -1,10 +1,25 @@
-use image::{GrayImage, RgbImage};
+use image::{GrayImage, Rgb32FImage};
 use super::{eo_transfer, oe_transfer};
 
 pub struct PrimaryBase {
     pub red: image::Rgb::<u8>,
     pub green: image::Rgb::<u8>,
     pub blue: image::Rgb::<u8>,
+    pub gray: image::Rgb::<u8>,
+    /// The bare board, i.e. what no coverage looks like.
+    pub board: image::Rgb::<u8>,
+}
+
+/// How a pixel is split into coverage of the individual yarns.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum Unmixing {
+    /// Non-negative least-squares mix of all yarns and the board, in linear RGB.
+    #[default]
+    LeastSquares,
+    /// Try each primary in turn and fall back to gray.
+    ///
+    /// This assumes a white board and black gray yarn.
+    Heuristic,
 }
 
 #[derive(Debug)]
@@ -14,6 +29,23 @@ struct LabBase {
     pub blue: Lab,
 }
 
+/// The colors which can be mixed, in linear RGB.
+///
+/// Order matches the coverage in `Primaries`, with the board in front.
+pub(crate) struct LinearBase([LinearRgb; 5]);
+
+/// Coverage of each yarn for every pixel of an image.
+pub struct Coverage {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) pixels: Vec<Primaries>,
+    /// Which of the yarns are planned at all.
+    pub(crate) used: [bool; 4],
+}
+
+/// Target images for the planner, per yarn.
+///
+/// These encode coverage: white is the bare board, black is fully covered by that yarn.
 pub struct ColorPlan {
     pub gray: GrayImage,
     pub red: GrayImage,
@@ -21,85 +53,199 @@ pub struct ColorPlan {
     pub blue: GrayImage,
 }
 
+/// Split an image in linear sRGB into coverage of each yarn.
 pub fn decouple(
-    image: &RgbImage,
+    image: &Rgb32FImage,
     primaries: &PrimaryBase,
-) -> ColorPlan {
-    let (w, h) = image.dimensions();
+    unmixing: Unmixing,
+) -> Coverage {
+    let (width, height) = image.dimensions();
+
+    let pixels: Vec<Primaries> = match unmixing {
+        Unmixing::Heuristic => {
+            let base = {
+                let red = image_rgb_to_lab(primaries.red);
+                let green = image_rgb_to_lab(primaries.green);
+                let blue = image_rgb_to_lab(primaries.blue);
+
+                LabBase {
+                    red,
+                    green,
+                    blue,
+                }
+            };
+
+            image
+                .pixels()
+                .map(|c: &image::Rgb::<f32>| {
+                    linear_srgb_to_oklab(LinearRgb(c.0))
+                })
+                .map(|lab| {
+                    let Primaries([l, r, g, b]) = decouple_pixel(lab, &base);
+                    // The heuristic estimates the lightness of gray, not its coverage.
+                    Primaries([1.0 - l, r, g, b])
+                })
+                .collect()
+        }
+        Unmixing::LeastSquares => {
+            let base = LinearBase::new(primaries);
+
+            image
+                .pixels()
+                .map(|c: &image::Rgb::<f32>| {
+                    LinearRgb(c.0)
+                })
+                .map(|rgb| {
+                    unmix_pixel(rgb, &base, [true; 4])
+                })
+                .collect()
+        }
+    };
+
+    Coverage {
+        width,
+        height,
+        pixels,
+        used: [true; 4],
+    }
+}
 
-    let base = {
-        let red = image_rgb_to_lab(primaries.red);
-        let green = image_rgb_to_lab(primaries.green);
-        let blue = image_rgb_to_lab(primaries.blue);
+/// The coverage target when only the gray yarn is used.
+///
+/// Each pixel is projected onto the line between board and yarn, weighted by luminance so that a
+/// white board with black yarn plans by the luminance of the image.
+pub fn decouple_gray(
+    image: &Rgb32FImage,
+    primaries: &PrimaryBase,
+) -> Coverage {
+    const LUMINANCE: [f32; 3] = [0.2126, 0.7152, 0.0722];
 
-        dbg!(green);
+    let (width, height) = image.dimensions();
+    let LinearRgb(board) = image_rgb_to_linear(primaries.board);
+    let LinearRgb(yarn) = image_rgb_to_linear(primaries.gray);
 
-        LabBase {
-            red,
-            green,
-            blue,
-        }
-    };
+    let contrast: f32 = (0..3)
+        .map(|i| LUMINANCE[i] * (yarn[i] - board[i]).powi(2))
+        .sum();
 
-    let lab: Vec<Primaries> = image
+    let pixels: Vec<Primaries> = image
         .pixels()
-        .map(|c: &image::Rgb::<u8>| {
-            image_rgb_to_lab(*c)
-        })
-        .map(|lab| {
-            decouple_pixel(lab, &base)
+        .map(|&image::Rgb(c)| {
+
+            let coverage = if contrast > 0.0 {
+                let along: f32 = (0..3)
+                    .map(|i| LUMINANCE[i] * (c[i] - board[i]) * (yarn[i] - board[i]))
+                    .sum();
+                along / contrast
+            } else {
+                // The yarn is invisible on this board, nothing to gain.
+                0.0
+            };
+
+            Primaries([coverage.clamp(0.0, 1.0), 0.0, 0.0, 0.0])
         })
         .collect();
 
-    let gray: Vec<u8> = lab
-        .iter()
-        .map(|&lab| oe_transfer(lab.0[0].max(0.0).min(1.0)))
-        .collect();
+    Coverage {
+        width,
+        height,
+        pixels,
+        used: [true, false, false, false],
+    }
+}
 
-    let red: Vec<u8> = lab
-        .iter()
-        .map(|&lab| oe_transfer(1.0 - lab.0[1].max(0.0).min(1.0)))
-        .collect();
+impl Coverage {
+    /// Encode the coverage as target images for the planner.
+    pub fn into_plan(self) -> ColorPlan {
+        let (w, h) = (self.width, self.height);
 
-    let green: Vec<u8> = lab
-        .iter()
-        .map(|&lab| oe_transfer(1.0 - lab.0[2].max(0.0).min(1.0)))
-        .collect();
+        let channel = |idx: usize| -> Vec<u8> {
+            self.pixels
+                .iter()
+                .map(|&p| oe_transfer(1.0 - p.0[idx].clamp(0.0, 1.0)))
+                .collect()
+        };
 
-    let blue: Vec<u8> = lab
-        .iter()
-        .map(|&lab| oe_transfer(1.0 - lab.0[3].max(0.0).min(1.0)))
-        .collect();
+        ColorPlan {
+            gray: GrayImage::from_raw(w, h, channel(0)).unwrap(),
+            red: GrayImage::from_raw(w, h, channel(1)).unwrap(),
+            green: GrayImage::from_raw(w, h, channel(2)).unwrap(),
+            blue: GrayImage::from_raw(w, h, channel(3)).unwrap(),
+        }
+    }
+}
 
-    ColorPlan {
-        gray: GrayImage::from_raw(w, h, gray).unwrap(),
-        red: GrayImage::from_raw(w, h, red).unwrap(),
-        green: GrayImage::from_raw(w, h, green).unwrap(),
-        blue: GrayImage::from_raw(w, h, blue).unwrap(),
+impl LinearBase {
+    pub(crate) fn new(primaries: &PrimaryBase) -> Self {
+        LinearBase([
+            image_rgb_to_linear(primaries.board),
+            image_rgb_to_linear(primaries.gray),
+            image_rgb_to_linear(primaries.red),
+            image_rgb_to_linear(primaries.green),
+            image_rgb_to_linear(primaries.blue),
+        ])
+    }
+
+    /// The color resulting from some coverage, the rest showing the board.
+    pub(crate) fn mix(&self, Primaries(coverage): &Primaries) -> [f32; 3] {
+        let board = 1.0 - coverage.iter().sum::<f32>();
+        let mut rgb = self.0[0].0.map(|v| v * board);
+
+        for (&f, color) in coverage.iter().zip(&self.0[1..]) {
+            for (c, v) in rgb.iter_mut().zip(color.0) {
+                *c += f * v;
+            }
+        }
+
+        rgb
     }
 }
 
+/// How far a color is from the closest mix of the board and used yarns.
+///
+/// This is the Euclidean distance in Oklab, where about `0.02` is just noticeable.
+pub(crate) fn mix_distance(
+    image::Rgb(c): image::Rgb::<f32>,
+    base: &LinearBase,
+    used: [bool; 4],
+) -> f32 {
+    let rgb = LinearRgb(c);
+    let mix = base.mix(&unmix_pixel(rgb, base, used));
+
+    let Lab(wanted) = linear_srgb_to_oklab(rgb);
+    let Lab(mixed) = linear_srgb_to_oklab(LinearRgb(mix));
+
+    (0..3)
+        .map(|i| (wanted[i] - mixed[i]).powi(2))
+        .sum::<f32>()
+        .sqrt()
+}
+
 #[derive(Clone, Copy, Debug)]
 struct Lab([f32; 3]);
 
 #[derive(Clone, Copy)]
 struct LinearRgb([f32; 3]);
 
+/// Coverage of gray, red, green and blue yarn.
 #[derive(Clone, Copy, Debug)]
-struct Primaries([f32; 4]);
+pub(crate) struct Primaries(pub(crate) [f32; 4]);
 
-fn image_rgb_to_lab(c: image::Rgb::<u8>) -> Lab {
+fn image_rgb_to_linear(c: image::Rgb::<u8>) -> LinearRgb {
     let image::Rgb([r, g, b]) = c;
 
-    let c = LinearRgb([
+    LinearRgb([
         eo_transfer(r),
         eo_transfer(g),
         eo_transfer(b),
-    ]);
+    ])
+}
 
-    linear_srgb_to_oklab(c)
+fn image_rgb_to_lab(c: image::Rgb::<u8>) -> Lab {
+    linear_srgb_to_oklab(image_rgb_to_linear(c))
 }
 
+#[allow(clippy::excessive_precision)]
 fn linear_srgb_to_oklab(c: LinearRgb) -> Lab {
     let LinearRgb([r, g, b]) = c;
 
@@ -118,29 +264,13 @@ fn linear_srgb_to_oklab(c: LinearRgb) -> Lab {
     ])
 }
 
-fn oklab_to_linear_srgb(c: Lab) -> LinearRgb {
-    let Lab([l, a, b]) = c;
-
-    let l_ = l + 0.3963377774 * a + 0.2158037573 * b;
-    let m_ = l - 0.1055613458 * a - 0.0638541728 * b;
-    let s_ = l - 0.0894841775 * a - 1.2914855480 * b;
-
-    let l = l_*l_*l_;
-    let m = m_*m_*m_;
-    let s = s_*s_*s_;
-
-    LinearRgb([
-		 4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
-		-1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
-		-0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s,
-    ])
-}
-
 fn cbrtf(v: f32) -> f32 {
-    v.powf(1.0/3.0)
+    // Wide gamut input can be negative in sRGB, which the real cube root handles.
+    v.cbrt()
 }
 
 fn decouple_pixel(lab: Lab, base: &LabBase) -> Primaries {
+    #[allow(clippy::neg_cmp_op_on_partial_ord)]
     fn smoothstep(x: f32, range: core::ops::Range<f32>) -> f32 {
         let x = (x - range.start) / (range.end - range.start);
         if !(x >= 0.0) {
@@ -258,5 +388,251 @@ fn decouple_pixel(lab: Lab, base: &LabBase) -> Primaries {
         return p;
     }
 
-    return Primaries([l, 0.0, 0.0, 0.0])
+    Primaries([l, 0.0, 0.0, 0.0])
+}
+
+/// Split a color into non-negative coverage of the board and all yarns.
+///
+/// The fractions sum to one, so this is a least-squares problem over the simplex spanned by the
+/// base colors. The optimum lies in the relative interior of one of its faces where it is the
+/// unconstrained projection onto that face's affine hull. We have few enough colors to just try
+/// all of them and keep the best feasible solution.
+fn unmix_pixel(c: LinearRgb, base: &LinearBase, used: [bool; 4]) -> Primaries {
+    const EPS: f64 = 1e-9;
+    let colors = &base.0;
+
+    // The board is always available.
+    let available = (0..4)
+        .filter(|&i| used[i])
+        .fold(1u32, |mask, i| mask | (1 << (i + 1)));
+
+    let target = c.0.map(f64::from);
+    let mut best: Option<(f64, f64, [f64; 5])> = None;
+
+    for subset in 1u32..(1 << colors.len()) {
+        // At most 4 colors can be affinely independent in 3 dimensions.
+        if subset.count_ones() > 4 || subset & !available != 0 {
+            continue;
+        }
+
+        let mut members = [0usize; 4];
+        let mut count = 0;
+        for i in (0..colors.len()).filter(|i| subset & (1 << i) != 0) {
+            members[count] = i;
+            count += 1;
+        }
+
+        let members = &members[..count];
+        let Some(fractions) = project_affine(&target, colors, members) else {
+            continue;
+        };
+
+        let fractions = &fractions[..count];
+
+        if fractions.iter().any(|&f| f < -EPS) {
+            continue;
+        }
+
+        let mut mix = [0.0f64; 5];
+        let mut reconstructed = [0.0f64; 3];
+        for (&idx, &f) in members.iter().zip(fractions) {
+            let f = f.max(0.0);
+            mix[idx] = f;
+            for (r, &v) in reconstructed.iter_mut().zip(&colors[idx].0) {
+                *r += f * f64::from(v);
+            }
+        }
+
+        let residual: f64 = reconstructed
+            .iter()
+            .zip(&target)
+            .map(|(r, t)| (r - t) * (r - t))
+            .sum();
+
+        // Amongst equally good mixes, prefer the one that uses the least yarn.
+        let yarn: f64 = mix[1..].iter().sum();
+
+        let better = match best {
+            None => true,
+            Some((best_residual, best_yarn, _)) => {
+                residual < best_residual - EPS
+                    || (residual < best_residual + EPS && yarn < best_yarn - EPS)
+            }
+        };
+
+        if better {
+            best = Some((residual, yarn, mix));
+        }
+    }
+
+    let (_, _, mix) = best.expect("A single color is always a feasible mix");
+    Primaries([mix[1], mix[2], mix[3], mix[4]].map(|f| f as f32))
+}
+
+/// Project the target onto the affine hull of some colors, as barycentric coordinates.
+///
+/// Only the first `members.len()` coordinates are meaningful. Returns `None` if the colors are
+/// affinely dependent.
+fn project_affine(
+    target: &[f64; 3],
+    colors: &[LinearRgb],
+    members: &[usize],
+) -> Option<[f64; 4]> {
+    let anchor = colors[members[0]].0.map(f64::from);
+    let dims = members.len() - 1;
+
+    // Directions from the anchor, one column for each other member.
+    let mut directions = [[0.0f64; 3]; 3];
+    for (direction, &idx) in directions.iter_mut().zip(&members[1..]) {
+        let c = colors[idx].0.map(f64::from);
+        *direction = [c[0] - anchor[0], c[1] - anchor[1], c[2] - anchor[2]];
+    }
+
+    let offset = [
+        target[0] - anchor[0],
+        target[1] - anchor[1],
+        target[2] - anchor[2],
+    ];
+
+    // Normal equations of the least-squares problem, as an augmented matrix.
+    let mut system = [[0.0f64; 4]; 3];
+    for i in 0..dims {
+        for j in 0..dims {
+            system[i][j] = dot3(&directions[i], &directions[j]);
+        }
+
+        system[i][dims] = dot3(&directions[i], &offset);
+    }
+
+    let solution = solve_gauss(&mut system[..dims], dims)?;
+
+    let mut fractions = [0.0f64; 4];
+    fractions[0] = 1.0 - solution[..dims].iter().sum::<f64>();
+    fractions[1..].copy_from_slice(&solution);
+    Some(fractions)
+}
+
+fn dot3(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// Solve a small linear system given as augmented rows, with partial pivoting.
+///
+/// Only the first `dims` entries of the solution are meaningful.
+fn solve_gauss(system: &mut [[f64; 4]], dims: usize) -> Option<[f64; 3]> {
+    for col in 0..dims {
+        let pivot = (col..dims)
+            .max_by(|&a, &b| system[a][col].abs().total_cmp(&system[b][col].abs()))?;
+
+        if system[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+
+        system.swap(col, pivot);
+
+        let (upper, lower) = system.split_at_mut(col + 1);
+        let pivot_row = &upper[col];
+        for row in &mut lower[..dims - col - 1] {
+            let factor = row[col] / pivot_row[col];
+            for (v, p) in row[col..=dims].iter_mut().zip(&pivot_row[col..=dims]) {
+                *v -= factor * p;
+            }
+        }
+    }
+
+    let mut solution = [0.0; 3];
+    for row in (0..dims).rev() {
+        let mut v = system[row][dims];
+        for k in row + 1..dims {
+            v -= system[row][k] * solution[k];
+        }
+
+        solution[row] = v / system[row][row];
+    }
+
+    Some(solution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn primaries() -> PrimaryBase {
+        PrimaryBase {
+            red: image::Rgb([200, 30, 40]),
+            green: image::Rgb([20, 160, 60]),
+            blue: image::Rgb([30, 50, 190]),
+            gray: image::Rgb([20, 20, 20]),
+            board: image::Rgb([240, 235, 225]),
+        }
+    }
+
+    fn assert_close(Primaries(found): Primaries, expected: [f32; 4]) {
+        for (found, expected) in found.iter().zip(expected) {
+            assert!((found - expected).abs() < 1e-3, "{found:?} instead of {expected:?}");
+        }
+    }
+
+    #[test]
+    fn unmix_recovers_a_mix() {
+        let base = LinearBase::new(&primaries());
+
+        for coverage in [[0.2, 0.3, 0.0, 0.0], [0.0, 0.0, 0.5, 0.0], [0.0, 0.0, 0.0, 0.0]] {
+            let mixed = LinearRgb(base.mix(&Primaries(coverage)));
+            assert_close(unmix_pixel(mixed, &base, [true; 4]), coverage);
+        }
+    }
+
+    #[test]
+    fn unmix_reproduces_any_mix_with_least_yarn() {
+        let base = LinearBase::new(&primaries());
+
+        // Four yarns and the board are dependent in three dimensions, so the split may differ.
+        let coverage = [0.0, 0.1, 0.4, 0.2];
+        let mixed = base.mix(&Primaries(coverage));
+        let unmixed = unmix_pixel(LinearRgb(mixed), &base, [true; 4]);
+
+        for (found, expected) in base.mix(&unmixed).iter().zip(mixed) {
+            assert!((found - expected).abs() < 1e-4);
+        }
+
+        assert!(unmixed.0.iter().sum::<f32>() <= coverage.iter().sum::<f32>() + 1e-4);
+    }
+
+    #[test]
+    fn unmix_stays_within_the_simplex() {
+        let base = LinearBase::new(&primaries());
+
+        // Brighter than the board and more saturated than any yarn.
+        for color in [[1.5, 1.5, 1.5], [0.0, 0.0, 1.0], [1.0, 0.0, 0.0]] {
+            let Primaries(coverage) = unmix_pixel(LinearRgb(color), &base, [true; 4]);
+            assert!(coverage.iter().all(|&f| f >= 0.0));
+            assert!(coverage.iter().sum::<f32>() <= 1.0 + 1e-6);
+        }
+    }
+
+    #[test]
+    fn unmix_only_uses_the_yarns_given() {
+        let base = LinearBase::new(&primaries());
+        let mixed = LinearRgb(base.mix(&Primaries([0.0, 0.5, 0.0, 0.0])));
+
+        let Primaries(coverage) = unmix_pixel(mixed, &base, [true, false, false, false]);
+        assert_eq!(coverage[1..], [0.0; 3]);
+        assert!(coverage[0] > 0.0);
+    }
+
+    #[test]
+    fn decouple_splits_each_pixel() {
+        let primaries = primaries();
+        let base = LinearBase::new(&primaries);
+        let expected = [0.1, 0.0, 0.0, 0.6];
+
+        let image = Rgb32FImage::from_pixel(2, 1, image::Rgb(base.mix(&Primaries(expected))));
+        let coverage = decouple(&image, &primaries, Unmixing::LeastSquares);
+
+        assert_eq!(coverage.pixels.len(), 2);
+        for &pixel in &coverage.pixels {
+            assert_close(pixel, expected);
+        }
+    }
 }
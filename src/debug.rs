@@ -17,16 +17,18 @@ pub fn dump_plan(
     let (w, h) = (w / 2.0, h / 2.0);
 
     for (window, lines) in plan.windows.iter().zip(lines) {
-        write!(into, r#"<polygon points=""#)?;
+        if !window.spanning {
+            write!(into, r#"<polygon points=""#)?;
 
-        for (x, y) in &window.points {
-            let x = x * w;
-            let y = y * h;
+            for (x, y) in &window.points {
+                let x = x * w;
+                let y = y * h;
 
-            write!(into, "{x},{y} ")?;
-        }
+                write!(into, "{x},{y} ")?;
+            }
 
-        write!(into, r#"" fill="none" stroke="black" />"#)?;
+            write!(into, r#"" fill="none" stroke="black" />"#)?;
+        }
 
         for (origin, r) in lines.ranges.iter().enumerate() {
             for &PolygonPoint(target) in &lines.idx_vec[r.start..r.end] {
@@ -59,19 +61,26 @@ pub fn dump_output(
     let (w, h) = (w as f32, h as f32);
     let (lx, ly) = (-w / 2.0, -h / 2.0);
     write!(into, r#"<svg viewBox="{lx} {ly} {w} {h}" xmlns="http://www.w3.org/2000/svg">"#)?;
+
+    let c_board = format_color_css(&primary.board);
+    write!(into, r#"<rect x="{lx}" y="{ly}" width="{w}" height="{h}" fill="{c_board}" />"#)?;
+
     let (w, h) = (w / 2.0, h / 2.0);
+    let c_gray = format_color_css(&primary.gray);
 
     for ((window, _lines), rgb) in plan.windows.iter().zip(lines).zip(sequences) {
-        write!(into, r#"<polygon points=""#)?;
+        if !window.spanning {
+            write!(into, r#"<polygon points=""#)?;
 
-        for (x, y) in &window.points {
-            let x = x * w;
-            let y = y * h;
+            for (x, y) in &window.points {
+                let x = x * w;
+                let y = y * h;
 
-            write!(into, "{x},{y} ")?;
-        }
+                write!(into, "{x},{y} ")?;
+            }
 
-        write!(into, r#"" fill="none" stroke="green" />"#)?;
+            write!(into, r#"" fill="none" stroke="green" />"#)?;
+        }
 
         for bw in rgb.black.sequence.windows(2) {
             let &[origin, target] = bw.try_into().unwrap();
@@ -84,7 +93,7 @@ pub fn dump_output(
             let y1 = y1 * h;
             let y2 = y2 * h;
 
-            write!(into, r#"<line x1="{x1}" y1="{y1}" x2="{x2}" y2="{y2}" stroke="black" stroke-opacity="40%" />"#)?;
+            write!(into, r#"<line x1="{x1}" y1="{y1}" x2="{x2}" y2="{y2}" stroke="{c_gray}" stroke-opacity="40%" />"#)?;
         }
 
         if !is_rgbish {
@@ -151,7 +160,7 @@ pub fn dump_output(
             let y1 = y1 * h;
             let y2 = y2 * h;
 
-            write!(into, r#"<line x1="{x1}" y1="{y1}" x2="{x2}" y2="{y2}" stroke="black" stroke-opacity="40%" />"#)?;
+            write!(into, r#"<line x1="{x1}" y1="{y1}" x2="{x2}" y2="{y2}" stroke="{c_gray}" stroke-opacity="40%" />"#)?;
         }
 
     }
@@ -0,0 +1,278 @@
+//! Spatially alternate yarns where a pixel would be a mix of them.
+//!
+//! The planner can only darken towards one yarn at a time, so a region between two yarn colors
+//! is better served by patches of either yarn than by both at half coverage. Every method here
+//! turns the coverage of each pixel into full coverage of a single yarn, or of none, while
+//! keeping the average coverage of every yarn.
+use rand_xoshiro::{
+    rand_core::SeedableRng,
+    rand_core::RngCore,
+    Xoshiro128Plus,
+};
+
+use crate::color::{Coverage, LinearBase, PrimaryBase, Primaries};
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Dither {
+    /// Diffuse the color error of each pixel onto its neighbours.
+    FloydSteinberg,
+    /// Threshold the coverage with a tiled blue noise mask.
+    BlueNoise,
+}
+
+pub fn dither(
+    coverage: &mut Coverage,
+    primaries: &PrimaryBase,
+    method: Dither,
+) {
+    match method {
+        Dither::FloydSteinberg => floyd_steinberg(coverage, primaries),
+        Dither::BlueNoise => blue_noise(coverage),
+    }
+}
+
+/// Error diffusion of the coverage vector.
+///
+/// Each pixel picks the single yarn (or the board) whose color is closest to the color of the
+/// coverage it should have, and passes on the difference in coverage to unvisited neighbours.
+fn floyd_steinberg(coverage: &mut Coverage, primaries: &PrimaryBase) {
+    let base = LinearBase::new(primaries);
+    let (w, h) = (coverage.width as usize, coverage.height as usize);
+
+    // The board first, so that it wins over any yarn indistinguishable from it.
+    let candidates: Vec<Primaries> = core::iter::once(Primaries([0.0; 4]))
+        .chain((0..4).filter(|&i| coverage.used[i]).map(|i| {
+            let mut c = [0.0; 4];
+            c[i] = 1.0;
+            Primaries(c)
+        }))
+        .collect();
+
+    let colors: Vec<[f32; 3]> = candidates.iter().map(|c| base.mix(c)).collect();
+
+    for y in 0..h {
+        for x in 0..w {
+            let idx = y * w + x;
+            let wanted = coverage.pixels[idx];
+            let wanted_rgb = base.mix(&wanted);
+
+            let (choice, _) = colors
+                .iter()
+                .map(|c| {
+                    (0..3).map(|i| (c[i] - wanted_rgb[i]).powi(2)).sum::<f32>()
+                })
+                .enumerate()
+                .fold((0, f32::INFINITY), |best, (i, d)| if d < best.1 { (i, d) } else { best });
+
+            let chosen = candidates[choice];
+            coverage.pixels[idx] = chosen;
+
+            let error: [f32; 4] = core::array::from_fn(|i| wanted.0[i] - chosen.0[i]);
+
+            let mut spread = |dx: isize, dy: usize, weight: f32| {
+                let nx = x as isize + dx;
+                let ny = y + dy;
+
+                if nx < 0 || nx as usize >= w || ny >= h {
+                    return;
+                }
+
+                let Primaries(neighbour) = &mut coverage.pixels[ny * w + nx as usize];
+                for (n, e) in neighbour.iter_mut().zip(error) {
+                    *n += e * weight;
+                }
+            };
+
+            spread(1, 0, 7.0 / 16.0);
+            spread(-1, 1, 3.0 / 16.0);
+            spread(0, 1, 5.0 / 16.0);
+            spread(1, 1, 1.0 / 16.0);
+        }
+    }
+}
+
+/// Ordered dithering with a blue noise threshold.
+///
+/// The yarns are stacked in a fixed order and the threshold selects the one it falls into. As
+/// the threshold is uniform over the tile, each yarn keeps its expected coverage.
+fn blue_noise(coverage: &mut Coverage) {
+    const SIZE: usize = 64;
+
+    let mask = void_and_cluster(SIZE);
+    let w = coverage.width as usize;
+
+    for (idx, pixel) in coverage.pixels.iter_mut().enumerate() {
+        let (x, y) = (idx % w, idx / w);
+        let threshold = mask[(y % SIZE) * SIZE + x % SIZE];
+
+        let mut stacked = 0.0;
+        let mut chosen = [0.0; 4];
+
+        for i in (0..4).filter(|&i| coverage.used[i]) {
+            stacked += pixel.0[i].clamp(0.0, 1.0);
+
+            if threshold < stacked {
+                chosen[i] = 1.0;
+                break;
+            }
+        }
+
+        *pixel = Primaries(chosen);
+    }
+}
+
+/// Generate a tileable blue noise threshold mask with values in `[0, 1)`.
+///
+/// This is Ulichney's void-and-cluster method: rank pixels by repeatedly taking the tightest
+/// cluster away from, or filling the largest void of, a binary pattern.
+fn void_and_cluster(size: usize) -> Vec<f32> {
+    const SIGMA: f32 = 1.5;
+
+    let len = size * size;
+
+    // The energy each set pixel contributes at some toroidal offset.
+    let kernel: Vec<f32> = (0..len)
+        .map(|idx| {
+            let (dx, dy) = (idx % size, idx / size);
+            let dx = dx.min(size - dx) as f32;
+            let dy = dy.min(size - dy) as f32;
+            (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+        })
+        .collect();
+
+    let toggle = |energy: &mut [f32], idx: usize, sign: f32| {
+        let (x, y) = (idx % size, idx / size);
+        for (other, e) in energy.iter_mut().enumerate() {
+            let dx = (other % size + size - x) % size;
+            let dy = (other / size + size - y) % size;
+            *e += sign * kernel[dy * size + dx];
+        }
+    };
+
+    let tightest_cluster = |pattern: &[bool], energy: &[f32]| -> usize {
+        (0..len)
+            .filter(|&i| pattern[i])
+            .max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    };
+
+    let largest_void = |pattern: &[bool], energy: &[f32]| -> usize {
+        (0..len)
+            .filter(|&i| !pattern[i])
+            .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    };
+
+    // Deterministic, so that the same image always dithers the same.
+    let mut rng = Xoshiro128Plus::from_seed([0x5a; 16]);
+    let mut pattern = vec![false; len];
+    let mut energy = vec![0.0f32; len];
+
+    let initial = len / 10;
+    let mut placed = 0;
+    while placed < initial {
+        let idx = rng.next_u32() as usize % len;
+        if !pattern[idx] {
+            pattern[idx] = true;
+            toggle(&mut energy, idx, 1.0);
+            placed += 1;
+        }
+    }
+
+    // Spread out the initial pattern until moving a pixel does not help.
+    for _ in 0..len {
+        let cluster = tightest_cluster(&pattern, &energy);
+        pattern[cluster] = false;
+        toggle(&mut energy, cluster, -1.0);
+
+        let void = largest_void(&pattern, &energy);
+        pattern[void] = true;
+        toggle(&mut energy, void, 1.0);
+
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut rank = vec![0usize; len];
+
+    {
+        let mut pattern = pattern.clone();
+        let mut energy = energy.clone();
+
+        for r in (0..initial).rev() {
+            let cluster = tightest_cluster(&pattern, &energy);
+            pattern[cluster] = false;
+            toggle(&mut energy, cluster, -1.0);
+            rank[cluster] = r;
+        }
+    }
+
+    for r in initial..len {
+        let void = largest_void(&pattern, &energy);
+        pattern[void] = true;
+        toggle(&mut energy, void, 1.0);
+        rank[void] = r;
+    }
+
+    rank.into_iter().map(|r| r as f32 / len as f32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn primaries() -> PrimaryBase {
+        PrimaryBase {
+            red: image::Rgb([255, 0, 0]),
+            green: image::Rgb([0, 255, 0]),
+            blue: image::Rgb([0, 0, 255]),
+            gray: image::Rgb([0, 0, 0]),
+            board: image::Rgb([255, 255, 255]),
+        }
+    }
+
+    fn uniform(size: u32, pixel: [f32; 4], used: [bool; 4]) -> Coverage {
+        Coverage {
+            width: size,
+            height: size,
+            pixels: vec![Primaries(pixel); (size * size) as usize],
+            used,
+        }
+    }
+
+    /// The average coverage of each yarn.
+    fn mean(coverage: &Coverage) -> [f32; 4] {
+        let count = coverage.pixels.len() as f32;
+        core::array::from_fn(|i| coverage.pixels.iter().map(|p| p.0[i]).sum::<f32>() / count)
+    }
+
+    fn assert_dithered(coverage: &Coverage) {
+        for Primaries(pixel) in &coverage.pixels {
+            assert!(pixel.iter().all(|&f| f == 0.0 || f == 1.0));
+            assert!(pixel.iter().sum::<f32>() <= 1.0);
+        }
+    }
+
+    #[test]
+    fn floyd_steinberg_keeps_the_average() {
+        let mut coverage = uniform(32, [0.3, 0.0, 0.0, 0.0], [true, false, false, false]);
+        dither(&mut coverage, &primaries(), Dither::FloydSteinberg);
+
+        assert_dithered(&coverage);
+        // Only the error diffused past the last row and column is lost.
+        assert!((mean(&coverage)[0] - 0.3).abs() < 0.02);
+    }
+
+    #[test]
+    fn blue_noise_keeps_the_average_of_each_yarn() {
+        let mut coverage = uniform(64, [0.2, 0.3, 0.0, 0.1], [true; 4]);
+        dither(&mut coverage, &primaries(), Dither::BlueNoise);
+
+        assert_dithered(&coverage);
+        // Over a whole tile the thresholds are spread evenly, so this is exact up to a pixel.
+        for (found, expected) in mean(&coverage).iter().zip([0.2, 0.3, 0.0, 0.1]) {
+            assert!((found - expected).abs() <= 1.0 / 4096.0, "{found} instead of {expected}");
+        }
+    }
+}
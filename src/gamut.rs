@@ -0,0 +1,60 @@
+//! Check which parts of an image a palette of yarn can reproduce.
+use image::{Rgb32FImage, RgbImage};
+
+use crate::color::{self, LinearBase, PrimaryBase};
+use crate::oe_transfer;
+
+pub struct Gamut {
+    /// The image grayed out, with out-of-gamut pixels highlighted.
+    pub overlay: RgbImage,
+    /// Number of pixels that no mix of yarn reproduces.
+    pub out_of_gamut: u64,
+    pub pixels: u64,
+}
+
+/// The color used to highlight pixels we can not reach.
+const HIGHLIGHT: [u8; 3] = [0xff, 0x00, 0xff];
+
+pub fn analyse(
+    image: &Rgb32FImage,
+    primaries: &PrimaryBase,
+    used: [bool; 4],
+    tolerance: f32,
+) -> Gamut {
+    let base = LinearBase::new(primaries);
+
+    let mut overlay = RgbImage::new(image.width(), image.height());
+    let mut out_of_gamut = 0;
+
+    for (pixel, out) in image.pixels().zip(overlay.pixels_mut()) {
+        let distance = color::mix_distance(*pixel, &base, used);
+
+        let image::Rgb([r, g, b]) = *pixel;
+        let luma = oe_transfer((0.2126 * r + 0.7152 * g + 0.0722 * b).clamp(0.0, 1.0));
+
+        *out = if distance > tolerance {
+            out_of_gamut += 1;
+            image::Rgb(HIGHLIGHT.map(|c| ((c as u16 + luma as u16) / 2) as u8))
+        } else {
+            // Wash out what we can reach, so the highlight stands out.
+            let washed = 0x80 + luma / 2;
+            image::Rgb([washed; 3])
+        };
+    }
+
+    Gamut {
+        overlay,
+        out_of_gamut,
+        pixels: u64::from(image.width()) * u64::from(image.height()),
+    }
+}
+
+impl Gamut {
+    pub fn percentage(&self) -> f32 {
+        if self.pixels == 0 {
+            return 0.0;
+        }
+
+        self.out_of_gamut as f32 / self.pixels as f32 * 100.0
+    }
+}
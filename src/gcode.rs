@@ -0,0 +1,313 @@
+//! G-code for an XY machine which wraps yarn around the pins of the board.
+//!
+//! Coordinates are in millimetres with the origin at the bottom left of the board. Each pin is
+//! wrapped by a full circle around it in the direction the yarn bends there (`G2` clockwise, `G3`
+//! counter-clockwise), the yarn being laid by the straight moves in between. The machine pauses
+//! (`M0`) for the operator to tie the yarn on and off, or to park it uncut where it continues in a
+//! later window, and changes tools (`M6`) when switching yarns: `T1` to `T3` for the three
+//! primaries and `T4` for the gray yarn. Comments name nails by their number on the template.
+//! Dwells between windows are `G4 S`, in seconds as Marlin and RepRapFirmware read it, where `G4 P`
+//! would be milliseconds.
+use std::io::{BufRead, Write};
+
+use eyre::{bail, eyre};
+
+use crate::color::PrimaryBase;
+use crate::plan::{PolygonPoint, RgbSequence, Sequence};
+use crate::poly::{Nails, Polygons};
+use crate::render::Canvas;
+use crate::wrap::{self, Turn};
+
+pub struct Machine {
+    /// Feed rate of moves between pins, in mm/min.
+    pub feed: f32,
+    /// Feed rate while wrapping around a pin, in mm/min.
+    pub wrap_feed: f32,
+    /// Radius of the circle the head moves around each pin, in mm.
+    pub wrap_radius: f32,
+    /// Dwell between two windows, in seconds.
+    pub window_pause: f32,
+}
+
+/// One continuous piece of yarn, as replayed from a program.
+pub struct Run {
+    pub tool: u32,
+    /// The nails wrapped, in order.
+    pub nails: Vec<usize>,
+}
+
+pub fn write(
+    mut into: impl Write,
+    plan: &Polygons,
+    sequences: &[RgbSequence],
+    is_rgbish: bool,
+    machine: &Machine,
+) -> Result<(), eyre::Report> {
+    let height = plan.board.height_mm;
+    let to_machine = |point: (f32, f32)| -> (f32, f32) {
+        let (x, y) = plan.board.to_mm(point);
+        (x, height - y)
+    };
+
+    let Machine { feed, wrap_feed, wrap_radius: r, window_pause } = *machine;
+
+    writeln!(into, "; yarn-line-art, board {} x {} mm", plan.board.width_mm, plan.board.height_mm)?;
+    writeln!(into, "G21 ; millimetres")?;
+    writeln!(into, "G90 ; absolute positioning")?;
+
+    let nails = plan.nails();
+    let mut current_tool = None;
+    let mut first_window = true;
+
+    for (idx, (window, rgb)) in plan.windows.iter().zip(sequences).enumerate() {
+        let mut yarns = vec![];
+        if is_rgbish {
+            yarns.push((1, &rgb.r));
+            yarns.push((2, &rgb.g));
+            yarns.push((3, &rgb.b));
+        }
+
+        yarns.push((4, &rgb.black));
+
+        let yarns: Vec<_> = yarns
+            .into_iter()
+            .filter(|(_, seq)| seq.sequence.len() > 1)
+            .collect();
+
+        if yarns.is_empty() {
+            continue;
+        }
+
+        if !first_window && window_pause > 0.0 {
+            writeln!(into, "G4 S{window_pause:.3} ; pause between windows")?;
+        }
+
+        first_window = false;
+        writeln!(into, "; window {idx}")?;
+
+        for (tool, seq) in yarns {
+            if current_tool != Some(tool) {
+                writeln!(into, "M6 T{tool}")?;
+                current_tool = Some(tool);
+            }
+
+            let path = wrap::on_board(&plan.board, &window.points, seq);
+            let nail = |point: usize| nails.of_window[idx][point];
+
+            let mut previous: Option<(f32, f32)> = None;
+            for (step, &PolygonPoint(point)) in seq.sequence.iter().enumerate() {
+                let (x, y) = to_machine(window.points[point]);
+
+                // Approach each pin from the side the yarn comes from.
+                let (ux, uy) = match previous {
+                    Some((px, py)) => {
+                        let (dx, dy) = (x - px, y - py);
+                        let len = (dx * dx + dy * dy).sqrt();
+                        if len > 0.0 { (dx / len, dy / len) } else { (-1.0, 0.0) }
+                    }
+                    None => (-1.0, 0.0),
+                };
+
+                let (ax, ay) = (x - ux * r, y - uy * r);
+                let (i, j) = (x - ax, y - ay);
+
+                if step == 0 {
+                    writeln!(into, "G0 X{ax:.4} Y{ay:.4}")?;
+                    match seq.linked_from {
+                        Some(from) => writeln!(into, "M0 ; pick up yarn from window {from} at nail {}", nail(point))?,
+                        None => writeln!(into, "M0 ; tie on at nail {}", nail(point))?,
+                    }
+                } else {
+                    writeln!(into, "G1 X{ax:.4} Y{ay:.4} F{feed}")?;
+                }
+
+                // The machine's y axis points up, which keeps the direction as seen on the board.
+                let arc = match path.turns[step] {
+                    Turn::CounterClockwise => "G3",
+                    Turn::Clockwise | Turn::Tie => "G2",
+                };
+
+                writeln!(into, "{arc} X{ax:.4} Y{ay:.4} I{i:.4} J{j:.4} F{wrap_feed}")?;
+
+                // Continue with a new piece, which is tied on by wrapping the same pin again.
+                if seq.breaks.contains(&step) {
+                    writeln!(into, "M0 ; tie off and on again at nail {}", nail(point))?;
+                    writeln!(into, "{arc} X{ax:.4} Y{ay:.4} I{i:.4} J{j:.4} F{wrap_feed}")?;
+                }
+
+                previous = Some((x, y));
+            }
+
+            let &PolygonPoint(last) = seq.sequence.last().unwrap();
+            match seq.linked_to {
+                Some(to) => writeln!(into, "M0 ; leave yarn uncut at nail {} for window {to}", nail(last))?,
+                None => writeln!(into, "M0 ; tie off at nail {}", nail(last))?,
+            }
+        }
+    }
+
+    writeln!(into, "M2")?;
+    Ok(())
+}
+
+/// Parse a program and recover the yarn it lays.
+///
+/// Each arc is a wrap around the pin at its center, which is identified with the closest nail.
+/// Yarn is continuous between wraps unless the machine pauses or changes tools in between.
+pub fn replay(
+    from: impl BufRead,
+    plan: &Polygons,
+    nails: &Nails,
+) -> Result<Vec<Run>, eyre::Report> {
+    // Centers are printed with limited precision, allow for that.
+    const SNAP_MM: f32 = 0.5;
+
+    let height = plan.board.height_mm;
+    let mut runs: Vec<Run> = vec![];
+    let mut attached = false;
+    let mut tool = 0;
+    let mut position = (0.0f32, 0.0f32);
+
+    for (number, line) in from.lines().enumerate() {
+        let line = line?;
+        let code = line.split(';').next().unwrap_or_default();
+
+        let mut words = vec![];
+        for word in code.split_whitespace() {
+            let mut chars = word.chars();
+            let letter = chars.next().unwrap().to_ascii_uppercase();
+            let value: f32 = chars
+                .as_str()
+                .parse()
+                .map_err(|_| eyre!("Line {}: malformed word {word}", number + 1))?;
+            words.push((letter, value));
+        }
+
+        let get = |letter: char| words.iter().find(|w| w.0 == letter).map(|w| w.1);
+
+        if let Some(t) = get('T') {
+            tool = t as u32;
+        }
+
+        if get('M').is_some() {
+            // Pauses are where the operator ties yarn, tool changes switch it.
+            attached = false;
+        }
+
+        let Some(g) = get('G') else {
+            continue;
+        };
+
+        let target = (get('X').unwrap_or(position.0), get('Y').unwrap_or(position.1));
+
+        if g == 2.0 || g == 3.0 {
+            let (Some(i), Some(j)) = (get('I'), get('J')) else {
+                bail!("Line {}: arcs need a center", number + 1);
+            };
+
+            let (cx, cy) = (position.0 + i, height - (position.1 + j));
+            let nail = nails.points
+                .iter()
+                .map(|&point| plan.board.to_mm(point))
+                .enumerate()
+                .map(|(idx, (x, y))| (idx, (x - cx).powi(2) + (y - cy).powi(2)))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .filter(|&(_, distance)| distance.sqrt() <= SNAP_MM)
+                .map(|(idx, _)| idx)
+                .ok_or_else(|| eyre!("Line {}: no nail at {cx}, {cy}", number + 1))?;
+
+            if !attached {
+                runs.push(Run { tool, nails: vec![] });
+                attached = true;
+            }
+
+            runs.last_mut().unwrap().nails.push(nail);
+        }
+
+        position = target;
+    }
+
+    Ok(runs)
+}
+
+/// Render replayed yarn the same way as `render::render`.
+pub fn render(
+    dimensions: (u32, u32),
+    plan: &Polygons,
+    nails: &Nails,
+    runs: &[Run],
+    primary: &PrimaryBase,
+) -> Result<image::RgbImage, eyre::Report> {
+    let mut canvas = Canvas::new(dimensions, primary);
+
+    for run in runs {
+        let yarn = match run.tool {
+            1 => primary.red,
+            2 => primary.green,
+            3 => primary.blue,
+            4 => primary.gray,
+            other => bail!("Unknown tool T{other}"),
+        };
+
+        let seq = Sequence {
+            sequence: run.nails.iter().map(|&n| PolygonPoint(n)).collect(),
+            ..Sequence::default()
+        };
+
+        canvas.sequence(&plan.board, &nails.points, &seq, yarn);
+    }
+
+    canvas.nails(plan);
+    Ok(canvas.to_rgb8())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_matches_render() {
+        let plan = crate::poly::read(&br#"{
+            "kind": "polygons",
+            "windows": [
+                { "points": [[-0.8, -0.8], [0.0, -0.8], [0.0, 0.0], [0.0, 0.8], [-0.8, 0.8]] },
+                { "points": [[0.0, -0.8], [0.8, -0.8], [0.8, 0.8], [0.0, 0.8], [0.0, 0.0]] }
+            ]
+        }"#[..]).unwrap();
+
+        let sequence = |points: &[usize], breaks: Vec<usize>| Sequence {
+            sequence: points.iter().map(|&p| PolygonPoint(p)).collect(),
+            breaks,
+            ..Sequence::default()
+        };
+
+        let sequences = [
+            RgbSequence {
+                r: sequence(&[0, 2, 4, 1, 3], vec![2]),
+                black: sequence(&[4, 1, 3, 0], vec![]),
+                ..RgbSequence::default()
+            },
+            RgbSequence {
+                b: sequence(&[0, 2, 4, 1], vec![]),
+                black: sequence(&[1, 3, 0, 2], vec![]),
+                ..RgbSequence::default()
+            },
+        ];
+
+        let primary = plan.primaries.to_color_base(&plan.board);
+        let machine = Machine { feed: 1000.0, wrap_feed: 200.0, wrap_radius: 3.0, window_pause: 1.5 };
+
+        let mut program = vec![];
+        write(&mut program, &plan, &sequences, true, &machine).unwrap();
+
+        let nails = plan.nails();
+        let runs = replay(&program[..], &plan, &nails).unwrap();
+        assert_eq!(runs.len(), 5, "the break starts another run");
+
+        let replayed = render((64, 64), &plan, &nails, &runs, &primary).unwrap();
+        let expected = crate::render::render((64, 64), &plan, &sequences, &primary, true);
+        let bare = crate::render::render((64, 64), &plan, &[], &primary, true);
+        assert!(expected != bare, "the yarn shows in the render");
+        assert!(replayed == expected);
+    }
+}
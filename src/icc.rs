@@ -0,0 +1,327 @@
+//! Convert images with an embedded ICC profile to linear sRGB.
+//!
+//! Only matrix/TRC profiles are supported, which covers the usual RGB working spaces such as
+//! Display P3 and Adobe RGB as well as gray profiles. Profiles based on lookup tables are
+//! rejected and should be handled by the caller, e.g. by assuming sRGB.
+use eyre::{bail, eyre};
+use image::Rgb32FImage;
+
+/// A parsed matrix/TRC profile.
+pub struct Profile {
+    /// The tone response curves of red, green and blue.
+    curves: [Curve; 3],
+    /// From linear device values to the D50 connection space.
+    to_xyz: [[f32; 3]; 3],
+}
+
+enum Curve {
+    Gamma(f32),
+    Table(Vec<f32>),
+    /// The parametric curve of ICC type `para`, always with all seven parameters.
+    Parametric([f32; 7]),
+}
+
+/// From XYZ relative to D50 to linear sRGB, with Bradford adaptation.
+#[allow(clippy::excessive_precision)]
+const XYZ_D50_TO_SRGB: [[f32; 3]; 3] = [
+    [3.1338561, -1.6168667, -0.4906146],
+    [-0.9787684, 1.9161415, 0.0334540],
+    [0.0719453, -0.2289914, 1.4052427],
+];
+
+impl Profile {
+    pub fn parse(data: &[u8]) -> Result<Self, eyre::Report> {
+        if data.len() < 132 {
+            bail!("ICC profile is truncated");
+        }
+
+        let color_space = &data[16..20];
+        let connection = &data[20..24];
+
+        if connection != b"XYZ " {
+            bail!("ICC profile without an XYZ connection space is not supported");
+        }
+
+        let tag_count = read_u32(data, 128)? as usize;
+        let tag = |signature: &[u8; 4]| -> Result<&[u8], eyre::Report> {
+            for idx in 0..tag_count {
+                let entry = 132 + idx * 12;
+                if data.get(entry..entry + 4) != Some(&signature[..]) {
+                    continue;
+                }
+
+                let offset = read_u32(data, entry + 4)? as usize;
+                let size = read_u32(data, entry + 8)? as usize;
+
+                return data
+                    .get(offset..offset + size)
+                    .ok_or_else(|| eyre!("ICC tag {} out of bounds", String::from_utf8_lossy(signature)));
+            }
+
+            Err(eyre!("ICC profile has no tag {}, only matrix/TRC profiles are supported", String::from_utf8_lossy(signature)))
+        };
+
+        match color_space {
+            b"RGB " => {
+                let curves = [
+                    Curve::parse(tag(b"rTRC")?)?,
+                    Curve::parse(tag(b"gTRC")?)?,
+                    Curve::parse(tag(b"bTRC")?)?,
+                ];
+
+                let columns = [
+                    read_xyz(tag(b"rXYZ")?)?,
+                    read_xyz(tag(b"gXYZ")?)?,
+                    read_xyz(tag(b"bXYZ")?)?,
+                ];
+
+                let to_xyz = core::array::from_fn(|row| {
+                    core::array::from_fn(|col| columns[col][row])
+                });
+
+                Ok(Profile { curves, to_xyz })
+            }
+            b"GRAY" => {
+                let curve = tag(b"kTRC")?;
+
+                // The gray axis is the D50 white point, which maps onto neutral sRGB.
+                let white = [0.9642, 1.0, 0.8249];
+                let to_xyz = core::array::from_fn(|row| {
+                    core::array::from_fn(|col| if row == col { white[row] } else { 0.0 })
+                });
+
+                Ok(Profile {
+                    curves: [Curve::parse(curve)?, Curve::parse(curve)?, Curve::parse(curve)?],
+                    to_xyz,
+                })
+            }
+            other => bail!("ICC color space {:?} is not supported", String::from_utf8_lossy(other)),
+        }
+    }
+
+    /// Convert encoded values in `[0, 1]` to linear sRGB, in place.
+    pub fn to_linear_srgb(&self, image: &mut Rgb32FImage) {
+        let matrix = multiply(&XYZ_D50_TO_SRGB, &self.to_xyz);
+
+        for pixel in image.pixels_mut() {
+            let device: [f32; 3] = core::array::from_fn(|i| self.curves[i].eval(pixel.0[i]));
+            pixel.0 = core::array::from_fn(|row| {
+                (0..3).map(|col| matrix[row][col] * device[col]).sum()
+            });
+        }
+    }
+}
+
+impl Curve {
+    fn parse(data: &[u8]) -> Result<Self, eyre::Report> {
+        match data.get(0..4) {
+            Some(b"curv") => {
+                let count = read_u32(data, 8)? as usize;
+                match count {
+                    0 => Ok(Curve::Gamma(1.0)),
+                    1 => Ok(Curve::Gamma(read_u16(data, 12)? as f32 / 256.0)),
+                    _ => {
+                        let table = (0..count)
+                            .map(|idx| Ok(read_u16(data, 12 + 2 * idx)? as f32 / 65535.0))
+                            .collect::<Result<_, eyre::Report>>()?;
+                        Ok(Curve::Table(table))
+                    }
+                }
+            }
+            Some(b"para") => {
+                let function = read_u16(data, 8)?;
+                let count = match function {
+                    0 => 1,
+                    1 => 3,
+                    2 => 4,
+                    3 => 5,
+                    4 => 7,
+                    other => bail!("Unknown parametric curve type {other}"),
+                };
+
+                let mut p = [0.0; 7];
+                for (idx, v) in p.iter_mut().enumerate().take(count) {
+                    *v = read_s15f16(data, 12 + 4 * idx)?;
+                }
+
+                // Normalize to the full form: Y = (aX+b)^g + e for X >= d, else Y = cX + f.
+                let [g, a, b, c, d, e, f] = p;
+
+                // These put the threshold at -b/a, which does not exist for a flat curve.
+                if matches!(function, 1 | 2) && a == 0.0 {
+                    bail!("Parametric curve of type {function} has no slope");
+                }
+
+                let full = match function {
+                    0 => [g, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+                    1 => [g, a, b, 0.0, -b / a, 0.0, 0.0],
+                    2 => [g, a, b, 0.0, -b / a, c, c],
+                    3 => [g, a, b, c, d, 0.0, 0.0],
+                    _ => [g, a, b, c, d, e, f],
+                };
+
+                Ok(Curve::Parametric(full))
+            }
+            _ => bail!("Unsupported ICC curve type"),
+        }
+    }
+
+    fn eval(&self, x: f32) -> f32 {
+        match self {
+            Curve::Gamma(g) => x.max(0.0).powf(*g),
+            Curve::Table(table) => {
+                let pos = x.clamp(0.0, 1.0) * (table.len() - 1) as f32;
+                let idx = (pos as usize).min(table.len() - 2);
+                let f = pos - idx as f32;
+                table[idx] * (1.0 - f) + table[idx + 1] * f
+            }
+            &Curve::Parametric([g, a, b, c, d, e, f]) => {
+                if x >= d {
+                    (a * x + b).max(0.0).powf(g) + e
+                } else {
+                    c * x + f
+                }
+            }
+        }
+    }
+}
+
+fn multiply(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    core::array::from_fn(|row| {
+        core::array::from_fn(|col| (0..3).map(|k| a[row][k] * b[k][col]).sum())
+    })
+}
+
+fn read_xyz(data: &[u8]) -> Result<[f32; 3], eyre::Report> {
+    if data.get(0..4) != Some(b"XYZ ") {
+        bail!("Expected an ICC XYZ tag");
+    }
+
+    Ok([read_s15f16(data, 8)?, read_s15f16(data, 12)?, read_s15f16(data, 16)?])
+}
+
+fn read_u32(data: &[u8], at: usize) -> Result<u32, eyre::Report> {
+    let bytes = data.get(at..at + 4).ok_or_else(|| eyre!("ICC profile is truncated"))?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u16(data: &[u8], at: usize) -> Result<u16, eyre::Report> {
+    let bytes = data.get(at..at + 2).ok_or_else(|| eyre!("ICC profile is truncated"))?;
+    Ok(u16::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_s15f16(data: &[u8], at: usize) -> Result<f32, eyre::Report> {
+    Ok(read_u32(data, at)? as i32 as f32 / 65536.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s15f16(v: f32) -> [u8; 4] {
+        ((v * 65536.0).round() as i32).to_be_bytes()
+    }
+
+    fn para(function: u16, params: &[f32]) -> Vec<u8> {
+        let mut tag = b"para\0\0\0\0".to_vec();
+        tag.extend(function.to_be_bytes());
+        tag.extend([0, 0]);
+        tag.extend(params.iter().flat_map(|&v| s15f16(v)));
+        tag
+    }
+
+    fn xyz(v: [f32; 3]) -> Vec<u8> {
+        let mut tag = b"XYZ \0\0\0\0".to_vec();
+        tag.extend(v.iter().flat_map(|&v| s15f16(v)));
+        tag
+    }
+
+    /// A profile with just a header and some tags.
+    fn profile(color_space: &[u8; 4], tags: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut data = vec![0; 128];
+        data[16..20].copy_from_slice(color_space);
+        data[20..24].copy_from_slice(b"XYZ ");
+        data.extend((tags.len() as u32).to_be_bytes());
+
+        let mut offset = 132 + 12 * tags.len();
+        let mut contents: Vec<u8> = vec![];
+
+        for (signature, tag) in tags {
+            data.extend(*signature);
+            data.extend((offset as u32).to_be_bytes());
+            data.extend((tag.len() as u32).to_be_bytes());
+
+            contents.extend(tag);
+            offset += tag.len();
+        }
+
+        data.extend(contents);
+        data
+    }
+
+    /// The sRGB primaries adapted to D50, which are the sRGB profile's colorants.
+    fn srgb(curve: Vec<u8>) -> Vec<u8> {
+        profile(b"RGB ", &[
+            (b"rTRC", curve.clone()),
+            (b"gTRC", curve.clone()),
+            (b"bTRC", curve),
+            (b"rXYZ", xyz([0.4360747, 0.2225045, 0.0139322])),
+            (b"gXYZ", xyz([0.3850649, 0.7168786, 0.0971045])),
+            (b"bXYZ", xyz([0.1430804, 0.0606169, 0.7141733])),
+        ])
+    }
+
+    fn convert(profile: &Profile, rgb: [f32; 3]) -> [f32; 3] {
+        let mut image = Rgb32FImage::from_pixel(1, 1, image::Rgb(rgb));
+        profile.to_linear_srgb(&mut image);
+        image.get_pixel(0, 0).0
+    }
+
+    fn assert_close(found: [f32; 3], expected: [f32; 3]) {
+        for (found, expected) in found.iter().zip(expected) {
+            assert!((found - expected).abs() < 1e-3, "{found:?} instead of {expected:?}");
+        }
+    }
+
+    #[test]
+    fn srgb_curve_matches_the_transfer_function() {
+        let curve = para(3, &[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045]);
+        let profile = Profile::parse(&srgb(curve)).unwrap();
+
+        for v in [0.0, 0.02, 0.04045, 0.2, 0.5, 0.8, 1.0] {
+            let expected = crate::eo_transfer_f32(v);
+            assert_close(convert(&profile, [v; 3]), [expected; 3]);
+        }
+    }
+
+    #[test]
+    fn srgb_colorants_map_onto_srgb() {
+        let profile = Profile::parse(&srgb(para(0, &[1.0]))).unwrap();
+
+        assert_close(convert(&profile, [1.0, 0.0, 0.0]), [1.0, 0.0, 0.0]);
+        assert_close(convert(&profile, [0.0, 1.0, 0.0]), [0.0, 1.0, 0.0]);
+        assert_close(convert(&profile, [0.0, 0.0, 1.0]), [0.0, 0.0, 1.0]);
+        assert_close(convert(&profile, [0.3, 0.6, 0.9]), [0.3, 0.6, 0.9]);
+    }
+
+    #[test]
+    fn gray_profile_stays_neutral() {
+        let mut gamma = b"curv\0\0\0\0".to_vec();
+        gamma.extend(1u32.to_be_bytes());
+        gamma.extend(512u16.to_be_bytes());
+
+        let profile = Profile::parse(&profile(b"GRAY", &[(b"kTRC", gamma)])).unwrap();
+        assert_close(convert(&profile, [0.5; 3]), [0.25; 3]);
+    }
+
+    #[test]
+    fn flat_parametric_curve_is_rejected() {
+        assert!(Profile::parse(&srgb(para(1, &[2.2, 0.0, 0.5]))).is_err());
+        assert!(Profile::parse(&srgb(para(2, &[2.2, 0.0, 0.5, 0.1]))).is_err());
+    }
+
+    #[test]
+    fn lookup_table_profile_is_rejected() {
+        assert!(Profile::parse(&profile(b"RGB ", &[(b"A2B0", vec![0; 32])])).is_err());
+    }
+}
@@ -0,0 +1,346 @@
+//! Printable build instructions, as pages of SVG.
+//!
+//! Every window gets a small diagram with its nails, followed by the numbered steps of each yarn
+//! through it. Nails are labeled by their number on the template, so a nail shared by several
+//! windows has the same label in each. Each step names the direction to wrap its nail in, and yarn
+//! which is too long for one piece is tied off and on again in between. Yarn continuing into
+//! another window is left uncut, to be picked up there. Steps flow through the columns of each page,
+//! with a checkpoint every few steps so a builder can verify their position before continuing.
+use std::io::Write;
+use std::path::Path;
+
+use crate::color::PrimaryBase;
+use crate::plan::{PolygonPoint, RgbSequence};
+use crate::poly::{Polygon, Polygons};
+use crate::wrap::{self, Turn};
+
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum Paper {
+    #[default]
+    A4,
+    Letter,
+}
+
+pub struct Layout {
+    pub paper: Paper,
+    /// Insert a checkpoint after this many steps of one yarn.
+    pub checkpoint_every: usize,
+}
+
+/// Page geometry, all in millimetres.
+const MARGIN: f32 = 15.0;
+const COLUMNS: usize = 3;
+const COLUMN_GAP: f32 = 6.0;
+const LINE: f32 = 5.0;
+const FONT: f32 = 3.5;
+const DIAGRAM: f32 = 40.0;
+
+enum Block<'a> {
+    Window {
+        idx: usize,
+        window: &'a Polygon,
+        /// The nail of each point, as numbered on the template.
+        nails: Vec<usize>,
+    },
+    Yarn {
+        name: &'static str,
+        color: image::Rgb<u8>,
+        start: String,
+        /// The yarn was left uncut in this window.
+        from_window: Option<usize>,
+    },
+    Step {
+        number: usize,
+        from: String,
+        to: String,
+        turn: Turn,
+    },
+    Checkpoint {
+        steps: usize,
+        at: String,
+    },
+    TieOff {
+        at: String,
+        /// Leave the yarn uncut, to continue in this window.
+        into_window: Option<usize>,
+    },
+}
+
+struct Placed<'a> {
+    block: Block<'a>,
+    x: f32,
+    y: f32,
+}
+
+/// Write all pages into a directory, as `page-001.svg` and so on.
+pub fn write(
+    dir: &Path,
+    plan: &Polygons,
+    sequences: &[RgbSequence],
+    primary: &PrimaryBase,
+    is_rgbish: bool,
+    layout: &Layout,
+) -> Result<(), eyre::Report> {
+    let blocks = blocks(plan, sequences, primary, is_rgbish, layout.checkpoint_every);
+    let pages = paginate(blocks, layout.paper);
+
+    std::fs::create_dir_all(dir)?;
+
+    let total = pages.len();
+    for (idx, page) in pages.iter().enumerate() {
+        let number = idx + 1;
+        let file = std::fs::File::create(dir.join(format!("page-{number:03}.svg")))?;
+        write_page(std::io::BufWriter::new(file), page, layout.paper, number, total)?;
+    }
+
+    Ok(())
+}
+
+fn blocks<'a>(
+    plan: &'a Polygons,
+    sequences: &[RgbSequence],
+    primary: &PrimaryBase,
+    is_rgbish: bool,
+    checkpoint_every: usize,
+) -> Vec<Block<'a>> {
+    let nails = plan.nails();
+    let mut blocks = vec![];
+
+    for (idx, (window, rgb)) in plan.windows.iter().zip(sequences).enumerate() {
+        let of_point = &nails.of_window[idx];
+        let name_of = |PolygonPoint(idx): PolygonPoint| -> String {
+            of_point[idx].to_string()
+        };
+
+        let mut yarns = vec![];
+        if is_rgbish {
+            yarns.push(("red", primary.red, &rgb.r));
+            yarns.push(("green", primary.green, &rgb.g));
+            yarns.push(("blue", primary.blue, &rgb.b));
+        }
+
+        yarns.push(("gray", primary.gray, &rgb.black));
+
+        let yarns: Vec<_> = yarns
+            .into_iter()
+            .filter(|(_, _, seq)| seq.sequence.len() > 1)
+            .collect();
+
+        if yarns.is_empty() {
+            continue;
+        }
+
+        blocks.push(Block::Window { idx, window, nails: of_point.clone() });
+
+        for (name, color, seq) in yarns {
+            blocks.push(Block::Yarn {
+                name,
+                color,
+                start: name_of(seq.sequence[0]),
+                from_window: seq.linked_from,
+            });
+
+            let path = wrap::on_board(&plan.board, &window.points, seq);
+            let steps = seq.sequence.windows(2).count();
+            for (number, step) in seq.sequence.windows(2).enumerate() {
+                let &[from, to] = step.try_into().unwrap();
+                let number = number + 1;
+
+                blocks.push(Block::Step {
+                    number,
+                    from: name_of(from),
+                    to: name_of(to),
+                    turn: path.turns[number],
+                });
+
+                if checkpoint_every > 0 && number % checkpoint_every == 0 && number < steps {
+                    blocks.push(Block::Checkpoint {
+                        steps: number,
+                        at: name_of(to),
+                    });
+                }
+
+                // Continue with a new piece of the same yarn.
+                if seq.breaks.contains(&number) {
+                    blocks.push(Block::TieOff {
+                        at: name_of(to),
+                        into_window: None,
+                    });
+                    blocks.push(Block::Yarn {
+                        name,
+                        color,
+                        start: name_of(to),
+                        from_window: None,
+                    });
+                }
+            }
+
+            blocks.push(Block::TieOff {
+                at: name_of(*seq.sequence.last().unwrap()),
+                into_window: seq.linked_to,
+            });
+        }
+    }
+
+    blocks
+}
+
+impl Paper {
+    /// Width and height in millimetres.
+    pub(crate) fn size(self) -> (f32, f32) {
+        match self {
+            Paper::A4 => (210.0, 297.0),
+            Paper::Letter => (215.9, 279.4),
+        }
+    }
+}
+
+impl Block<'_> {
+    fn height(&self) -> f32 {
+        match self {
+            Block::Window { .. } => DIAGRAM + 2.0 * LINE,
+            Block::Yarn { .. } => 2.0 * LINE,
+            Block::Step { .. } => LINE,
+            Block::Checkpoint { .. } => 1.5 * LINE,
+            Block::TieOff { .. } => 1.5 * LINE,
+        }
+    }
+}
+
+/// Flow the blocks through the columns of as many pages as necessary.
+fn paginate(blocks: Vec<Block<'_>>, paper: Paper) -> Vec<Vec<Placed<'_>>> {
+    let (w, h) = paper.size();
+    let column_width = (w - 2.0 * MARGIN - (COLUMNS - 1) as f32 * COLUMN_GAP) / COLUMNS as f32;
+    // Leave room for the page footer.
+    let bottom = h - MARGIN - LINE;
+
+    let mut pages = vec![vec![]];
+    let mut column = 0;
+    let mut y = MARGIN;
+
+    for block in blocks {
+        let height = block.height();
+
+        if y + height > bottom {
+            column += 1;
+            y = MARGIN;
+
+            if column == COLUMNS {
+                pages.push(vec![]);
+                column = 0;
+            }
+        }
+
+        let x = MARGIN + column as f32 * (column_width + COLUMN_GAP);
+        pages.last_mut().unwrap().push(Placed { block, x, y });
+        y += height;
+    }
+
+    pages
+}
+
+fn write_page(
+    mut into: impl Write,
+    page: &[Placed<'_>],
+    paper: Paper,
+    number: usize,
+    total: usize,
+) -> Result<(), eyre::Report> {
+    let (w, h) = paper.size();
+    write!(into, r#"<svg width="{w}mm" height="{h}mm" viewBox="0 0 {w} {h}" xmlns="http://www.w3.org/2000/svg" font-family="sans-serif" font-size="{FONT}">"#)?;
+    write!(into, r#"<rect width="{w}" height="{h}" fill="white" />"#)?;
+
+    for placed in page {
+        let (x, y) = (placed.x, placed.y);
+        let baseline = y + LINE * 0.8;
+
+        match &placed.block {
+            Block::Window { idx, window, nails } => {
+                write!(into, r#"<text x="{x}" y="{baseline}" font-weight="bold" font-size="{}">Window {idx}</text>"#, FONT * 1.4)?;
+                write_diagram(&mut into, window, nails, x, y + 1.5 * LINE)?;
+            }
+            Block::Yarn { name, color, start, from_window } => {
+                let [r, g, b] = color.0;
+                let swatch_y = y + LINE * 0.2;
+                write!(into, r##"<rect x="{x}" y="{swatch_y}" width="{FONT}" height="{FONT}" fill="#{r:02x}{g:02x}{b:02x}" stroke="black" stroke-width="0.2" />"##)?;
+                let text_x = x + FONT * 1.5;
+                write!(into, r#"<text x="{text_x}" y="{baseline}" font-weight="bold">{} yarn</text>"#, escape(name))?;
+                let baseline = baseline + LINE;
+                match from_window {
+                    Some(window) => write!(into, r#"<text x="{x}" y="{baseline}">Pick up from window {window} at nail {}</text>"#, escape(start))?,
+                    None => write!(into, r#"<text x="{x}" y="{baseline}">Tie on at nail {}</text>"#, escape(start))?,
+                }
+            }
+            Block::Step { number, from, to, turn: Turn::Tie } => {
+                write!(into, r#"<text x="{x}" y="{baseline}">{number}. from {} to {}</text>"#, escape(from), escape(to))?;
+            }
+            Block::Step { number, from, to, turn } => {
+                write!(into, r#"<text x="{x}" y="{baseline}">{number}. from {} to {}, {}</text>"#, escape(from), escape(to), turn.label())?;
+            }
+            Block::Checkpoint { steps, at } => {
+                let rule = y + 0.25 * LINE;
+                let x2 = x + 50.0;
+                write!(into, r#"<line x1="{x}" y1="{rule}" x2="{x2}" y2="{rule}" stroke="black" stroke-width="0.3" />"#)?;
+                let baseline = baseline + 0.25 * LINE;
+                write!(into, r#"<text x="{x}" y="{baseline}" font-style="italic">Checkpoint: {steps} steps, at nail {}</text>"#, escape(at))?;
+            }
+            Block::TieOff { at, into_window: None } => {
+                write!(into, r#"<text x="{x}" y="{baseline}" font-weight="bold">Tie off at nail {}</text>"#, escape(at))?;
+            }
+            Block::TieOff { at, into_window: Some(window) } => {
+                write!(into, r#"<text x="{x}" y="{baseline}" font-weight="bold">Leave uncut at nail {}, for window {window}</text>"#, escape(at))?;
+            }
+        }
+    }
+
+    let footer_x = w / 2.0;
+    let footer_y = h - MARGIN / 2.0;
+    write!(into, r#"<text text-anchor="middle" x="{footer_x}" y="{footer_y}">Page {number} / {total}</text>"#)?;
+
+    write!(into, r#"</svg>"#)?;
+    Ok(())
+}
+
+/// A small outline of the window with each nail labeled.
+fn write_diagram(
+    mut into: impl Write,
+    window: &Polygon,
+    nails: &[usize],
+    x: f32,
+    y: f32,
+) -> Result<(), eyre::Report> {
+    let (mut min, mut max) = ((f32::MAX, f32::MAX), (f32::MIN, f32::MIN));
+    for &(px, py) in &window.points {
+        min = (min.0.min(px), min.1.min(py));
+        max = (max.0.max(px), max.1.max(py));
+    }
+
+    let extent = (max.0 - min.0).max(max.1 - min.1).max(f32::EPSILON);
+    // Leave some room for labels at the border.
+    let scale = (DIAGRAM - 6.0) / extent;
+    let place = |(px, py): (f32, f32)| -> (f32, f32) {
+        (x + 3.0 + (px - min.0) * scale, y + 3.0 + (py - min.1) * scale)
+    };
+
+    if !window.spanning {
+        write!(into, r#"<polygon points=""#)?;
+        for &point in &window.points {
+            let (px, py) = place(point);
+            write!(into, "{px},{py} ")?;
+        }
+        write!(into, r#"" fill="none" stroke="black" stroke-width="0.3" />"#)?;
+    }
+
+    let label = FONT * 0.5;
+    for (&point, nail) in window.points.iter().zip(nails) {
+        let (px, py) = place(point);
+        write!(into, r#"<circle cx="{px}" cy="{py}" r="0.5" />"#)?;
+        write!(into, r#"<text x="{px}" y="{py}" dx="0.7" dy="-0.7" font-size="{label}">{nail}</text>"#)?;
+    }
+
+    Ok(())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
@@ -1,154 +1,521 @@
+mod animate;
 mod atomicf32;
+mod bom;
 mod color;
 mod debug;
+mod dither;
+mod gamut;
+mod gcode;
+mod icc;
+mod instructions;
 mod output;
 mod poly;
 mod plan;
+mod render;
+mod template;
+mod wrap;
 
 use core::sync::atomic::{AtomicU32, Ordering};
 use atomicf32::AtomicF32;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
-use clap::Parser;
+use clap::{CommandFactory, Parser, Subcommand};
 
-use rayon::prelude::{ParallelBridge, IntoParallelIterator, ParallelIterator};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 
+/// Plan line art of yarn wrapped around nails, and everything needed to build it.
+///
+/// Without a subcommand, plans an image as `plan` does.
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(flatten)]
+    plan: Option<Args>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Plan the yarn for an image, also the default without a subcommand.
+    Plan(Args),
+    /// Regenerate all artefacts from a previously written plan, without planning.
+    Render(RenderArgs),
+    /// Report the parts of an image which the yarn can not reproduce.
+    Gamut(GamutArgs),
+    /// Replay a G-code program into a simulated render, to check it.
+    GcodeRender(GcodeRenderArgs),
+}
+
+#[derive(clap::Args)]
 struct Args {
     #[clap(long = "rgb", default_value = "false")]
     rgb: bool,
+    /// How pixels are split into yarn colors in `--rgb` mode.
+    #[clap(long = "unmix", value_enum, default_value_t)]
+    unmix: color::Unmixing,
+    /// Alternate yarns spatially instead of mixing them in a pixel.
+    #[clap(long = "dither", value_enum)]
+    dither: Option<dither::Dither>,
+    // Clap leaves the group of a struct with flattened fields empty, but needs a member to tell
+    // whether the plan arguments were given at all.
+    #[clap(group = "Args")]
     image: PathBuf,
     #[clap(long = "circle", default_value = "examples/c2.json")]
     circle: PathBuf,
-    #[clap(long = "debug-template", default_value = "target/template.svg")]
+    /// Mixed into the random choices of planning.
+    #[clap(long = "seed", default_value = "0")]
+    seed: u64,
+    /// Keep the windows of an existing plan, only replanning those selected by `--windows`.
+    #[clap(long = "resume")]
+    resume: Option<PathBuf>,
+    /// Indices of the windows to replan, all other are taken from `--resume`.
+    #[clap(long = "windows", value_delimiter = ',', requires = "resume")]
+    windows: Vec<usize>,
+    #[clap(flatten)]
+    board: BoardSize,
+    #[clap(flatten)]
+    budget: Budget,
+    /// Continue each yarn through neighbouring windows sharing a nail, instead of tying it off.
+    #[clap(long = "continuous", default_value = "false")]
+    continuous: bool,
+    /// How to choose the nail each yarn starts at, unless continuing from another window.
+    ///
+    /// With `previous-color`, yarns follow each other as red, green, blue, then gray, so this
+    /// needs `--rgb`.
+    #[clap(long = "start", value_enum, default_value_t)]
+    start: plan::Start,
+    /// Nails to try with `--start best`, spread around the window, or 0 for all.
+    #[clap(long = "start-candidates", default_value = "8")]
+    start_candidates: usize,
+    /// After planning the windows, lay up to this many threads of each yarn spanning several of
+    /// them, across the separators between windows.
+    #[clap(long = "spanning-threads")]
+    spanning_threads: Option<u32>,
+    /// Tie off and continue with a new piece of yarn after at most this many metres.
+    #[clap(long = "max-run-m")]
+    max_run_m: Option<f32>,
+    /// Save the mask, target and result of each planned window into `debug/` of the output.
+    #[clap(long = "debug-images", default_value = "false")]
+    debug_images: bool,
+    #[clap(flatten)]
+    artefacts: Artefacts,
+}
+
+/// Overrides of the physical board in the definition.
+#[derive(clap::Args)]
+struct BoardSize {
+    #[clap(long = "board-width-mm")]
+    board_width_mm: Option<f32>,
+    #[clap(long = "board-height-mm")]
+    board_height_mm: Option<f32>,
+    #[clap(long = "nail-diameter-mm")]
+    nail_diameter_mm: Option<f32>,
+}
+
+/// How much yarn to lay, instead of as much as covers the image.
+///
+/// Each yarn's budget is shared out between the windows by how much coverage they ask for. What
+/// some windows leave unspent goes to those which stopped for want of it. Windows lay no more
+/// threads than their iteration limit all the same.
+#[derive(clap::Args)]
+struct Budget {
+    /// All yarns together, where those without a budget of their own share what is left by how
+    /// much coverage they are asked for. Threads spanning windows come on top.
+    #[clap(long = "budget-m")]
+    total_m: Option<f32>,
+    /// The red yarn, instead of a share of the total.
+    #[clap(long = "red-budget-m")]
+    red_m: Option<f32>,
+    /// The green yarn, instead of a share of the total.
+    #[clap(long = "green-budget-m")]
+    green_m: Option<f32>,
+    /// The blue yarn, instead of a share of the total.
+    #[clap(long = "blue-budget-m")]
+    blue_m: Option<f32>,
+    /// The gray yarn, instead of a share of the total.
+    #[clap(long = "gray-budget-m")]
+    gray_m: Option<f32>,
+}
+
+/// A budget shared out between yarns and windows.
+struct Shares {
+    /// For each yarn, the budget of every window in millimetres.
+    windows: Vec<Vec<Option<f32>>>,
+    /// The budget of each yarn which has one, in metres.
+    of_yarn: BTreeMap<String, f32>,
+    /// For each yarn, how much coverage every window asks for.
+    darkness: Vec<Vec<f32>>,
+}
+
+/// Everything written from a finished plan.
+///
+/// All paths are relative to the output directory, nothing is written outside of it.
+#[derive(clap::Args)]
+struct Artefacts {
+    #[clap(long = "out-dir", default_value = "target")]
+    out_dir: PathBuf,
+    #[clap(long = "debug-template", default_value = "template.svg")]
     debug_template: PathBuf,
-    #[clap(long = "debug-plan", default_value = "target/plan.svg")]
+    #[clap(long = "debug-plan", default_value = "plan.svg")]
     debug_plan: PathBuf,
+    /// A simulated picture of the finished board.
+    #[clap(long = "render", default_value = "render.png")]
+    render: PathBuf,
+    /// Replay laying the yarn into this file, or directory of frames.
+    #[clap(long = "animation")]
+    animation: Option<PathBuf>,
+    #[clap(long = "animation-format", value_enum, default_value_t)]
+    animation_format: animate::Format,
+    #[clap(long = "segments-per-frame", default_value = "50")]
+    segments_per_frame: usize,
+    #[clap(long = "frame-delay-ms", default_value = "40")]
+    frame_delay_ms: u32,
+    /// Write printable build instructions into this directory.
+    #[clap(long = "instructions")]
+    instructions: Option<PathBuf>,
+    #[clap(long = "paper", value_enum, default_value_t)]
+    paper: instructions::Paper,
+    /// Steps of a yarn between two checkpoints in the instructions.
+    #[clap(long = "checkpoint-every", default_value = "25")]
+    checkpoint_every: usize,
+    /// Write a 1:1 drilling template of the nails into this directory.
+    #[clap(long = "template")]
+    template: Option<PathBuf>,
+    /// Write a G-code program for a yarn wrapping machine.
+    #[clap(long = "gcode")]
+    gcode: Option<PathBuf>,
+    /// Feed rate between pins, in mm/min.
+    #[clap(long = "gcode-feed", default_value = "3000")]
+    gcode_feed: f32,
+    /// Feed rate while wrapping a pin, in mm/min.
+    #[clap(long = "gcode-wrap-feed", default_value = "1000")]
+    gcode_wrap_feed: f32,
+    /// Radius of the wrapping motion around each pin, in mm.
+    #[clap(long = "gcode-wrap-radius", default_value = "2.0")]
+    gcode_wrap_radius: f32,
+    /// Dwell between two windows, in seconds.
+    #[clap(long = "gcode-window-pause", default_value = "0")]
+    gcode_window_pause: f32,
+    /// The bill of materials: yarn, nails, knots and build time.
+    #[clap(long = "bom", default_value = "bom.txt")]
+    bom: PathBuf,
+    /// Yarn used up by wrapping a nail once, in mm.
+    #[clap(long = "wrap-allowance-mm", default_value = "5")]
+    wrap_allowance_mm: f32,
+    /// Length of yarn on one spool, in m.
+    #[clap(long = "spool-length-m", default_value = "100")]
+    spool_length_m: f32,
+    /// Time to lay one segment between two nails, in seconds.
+    #[clap(long = "segment-seconds", default_value = "10")]
+    segment_seconds: f32,
+}
+
+#[derive(clap::Args)]
+struct RenderArgs {
+    /// The plan, as written to the section list.
+    plan: PathBuf,
+    /// Use this definition instead of the one recorded in the plan.
+    #[clap(long = "circle")]
+    circle: Option<PathBuf>,
+    #[clap(flatten)]
+    artefacts: Artefacts,
+}
+
+#[derive(clap::Args)]
+struct GcodeRenderArgs {
+    program: PathBuf,
+    #[clap(long = "circle", default_value = "examples/c2.json")]
+    circle: PathBuf,
+    #[clap(flatten)]
+    board: BoardSize,
+    /// Resolution of the render, usually that of the planned image.
+    #[clap(long = "width")]
+    width: u32,
+    #[clap(long = "height")]
+    height: u32,
+    #[clap(long = "out-dir", default_value = "target")]
+    out_dir: PathBuf,
+    #[clap(long = "render", default_value = "gcode-render.png")]
+    render: PathBuf,
+}
+
+#[derive(clap::Args)]
+struct GamutArgs {
+    #[clap(long = "rgb", default_value = "false")]
+    rgb: bool,
+    image: PathBuf,
+    #[clap(long = "circle", default_value = "examples/c2.json")]
+    circle: PathBuf,
+    /// Distance in Oklab up to which a color counts as reproduced.
+    #[clap(long = "tolerance", default_value = "0.02")]
+    tolerance: f32,
+    #[clap(long = "out-dir", default_value = "target")]
+    out_dir: PathBuf,
+    #[clap(long = "overlay", default_value = "gamut.png")]
+    overlay: PathBuf,
 }
 
 fn main() -> Result<(), eyre::Report> {
-    let args = Args::parse();
+    let cli = Cli::parse();
 
-    let plan = poly::read({
-        std::fs::File::open(args.circle)?
-    })?;
+    match cli.command {
+        Some(Command::Plan(args)) => plan(args),
+        Some(Command::Render(args)) => render(args),
+        Some(Command::Gamut(args)) => gamut(args),
+        Some(Command::GcodeRender(args)) => gcode_render(args),
+        None => match cli.plan {
+            Some(args) => plan(args),
+            None => Cli::command()
+                .error(clap::error::ErrorKind::MissingRequiredArgument, "no image to plan")
+                .exit(),
+        },
+    }
+}
 
-    let image = image::io::Reader::new({
-        let file = std::fs::File::open(args.image)?;
-        std::io::BufReader::new(file)
-    });
+fn plan(args: Args) -> Result<(), eyre::Report> {
+    if args.start == plan::Start::PreviousColor && !args.rgb {
+        eyre::bail!("`--start previous-color` needs `--rgb`, a single yarn has no previous color");
+    }
 
-    let image = image::io::Reader::with_guessed_format(image)?;
-    let image = image::io::Reader::decode(image)?.into_rgb8();
-    let dimensions = image.dimensions();
+    let definition = std::fs::read(&args.circle)?;
+    let mut plan = poly::read(&definition[..])?;
+    args.board.apply(&mut plan.board);
 
-    let mut lines = vec![];
-    for window in &plan.windows {
-        lines.push(plan::permissible_lines(&window, dimensions));
+    if let Some(count) = args.spanning_threads {
+        plan.add_spanning(count);
     }
 
+    let image = load_image(&args.image)?;
+    let dimensions = image.dimensions();
+    let input_hash = output::fnv1a(&std::fs::read(&args.image)?);
+
+    args.artefacts.check()?;
+    std::fs::create_dir_all(&args.artefacts.out_dir)?;
+
+    let debug_images = if args.debug_images {
+        let dir = args.artefacts.path("debug")?;
+        std::fs::create_dir_all(&dir)?;
+        Some(dir)
+    } else {
+        None
+    };
+
+    let nails = plan.nails();
+    let primary: color::PrimaryBase = plan.primaries.to_color_base(&plan.board);
+
+    let settings = plan::Settings {
+        seed: args.seed,
+        debug_images,
+        wraps: plan::Wraps::new(&nails),
+        max_run_mm: args.max_run_m.map(|metres| metres * 1000.0),
+    };
+
+    let lines = all_lines(&plan, &nails);
+
     debug::dump_plan(
-        std::fs::File::create(args.debug_template)?,
+        std::fs::File::create(args.artefacts.path(&args.artefacts.debug_template)?)?,
         dimensions,
         &plan,
         &lines,
     )?;
 
     let mut sequences = lines.iter().map(|_| plan::RgbSequence::default()).collect::<Vec<_>>();
+    let mut replan = vec![true; plan.windows.len()];
+    let mut seeds = vec![args.seed; plan.windows.len()];
 
     let preliminary_break = AtomicU32::new(0);
     let regions_covered = AtomicU32::new(0);
     let yarn_length = AtomicF32::new();
 
-    let primary: color::PrimaryBase = plan.primaries.to_color_base();
+    if let Some(path) = &args.resume {
+        let saved = output::Plan::read(std::io::BufReader::new(std::fs::File::open(path)?))?;
 
-    if args.rgb {
-        let color_plan = color::decouple(&image, &primary);
-        let channels = [&color_plan.red, &color_plan.green, &color_plan.blue];
+        if saved.run.image_size != [dimensions.0, dimensions.1] {
+            eyre::bail!(
+                "Resumed plan was made at {:?} pixels, the image has {dimensions:?}",
+                saved.run.image_size,
+            );
+        }
 
-        for (idx, channel) in [0, 1, 2].into_iter().zip(channels) {
-            let tasks = plan.windows.iter().zip(&mut lines).zip(&mut sequences);
+        if saved.run.input_hash != input_hash {
+            eyre::bail!("Resumed plan was made from a different image");
+        }
 
-            let class = plan::LineClass {
-                of: 4,
-                idx,
-            };
+        if saved.run.rgb != args.rgb {
+            eyre::bail!("Resumed plan must agree on `--rgb`");
+        }
+
+        // Kept windows are rendered in the colors of the definition, which must be theirs.
+        if saved.run.palette != palette(&primary) {
+            eyre::bail!("Resumed plan was made with other yarn colors");
+        }
+
+        if saved.run.spanning_threads != args.spanning_threads {
+            eyre::bail!("Resumed plan must agree on `--spanning-threads`");
+        }
+
+        // Kept windows were laid out on the saved board, the replanned ones must match them.
+        if saved.run.definition != serde_json::from_slice::<serde_json::Value>(&definition)? {
+            eyre::bail!("Resumed plan was made from a different definition");
+        }
+
+        let board = &saved.run.board;
+        let size = [plan.board.width_mm, plan.board.height_mm, plan.board.nail_diameter_mm];
+        if [board.width_mm, board.height_mm, board.nail_diameter_mm] != size {
+            eyre::bail!("Resumed plan was made on a board of another size or with other nails");
+        }
+
+        sequences = saved.sequences(&plan)?;
+        replan.fill(false);
+
+        for &idx in &args.windows {
+            *replan
+                .get_mut(idx)
+                .ok_or_else(|| eyre::eyre!("No window {idx}, there are {}", plan.windows.len()))? = true;
+        }
 
-            tasks
-                .par_bridge()
-                .into_par_iter()
-                .try_for_each(|((window, lines), rgb)| {
-                    // FIXME: the blending mode in planning makes no sense here. We add chroma, but it
-                    // does luminance planning. If some region is a mix of red/white it won't plan any
-                    // red but everything else. What.
-                    let seq = plan::plan(&channel, window, lines, &class)?;
+        // Threads spanning windows are planned over all the others, so follow any change to them.
+        if let Some(idx) = plan.windows.iter().position(|window| window.spanning) {
+            replan[idx] |= replan.contains(&true);
+        }
+
+        let kept = sequences.iter_mut().zip(&replan).enumerate().filter(|(_, (_, &replan))| !replan);
+        for (index, (rgb, _)) in kept {
+            seeds[index] = saved.windows[index].seed.unwrap_or(saved.run.seed);
 
-                    preliminary_break
-                        .fetch_add(
-                            u32::from(matches!(seq.break_reason, plan::BreakReason::EndOfIteration)),
-                            Ordering::Relaxed,
-                        );
-                    regions_covered.fetch_add(1, Ordering::Relaxed);
-                    yarn_length.fetch_add(seq.yarn_length);
+            for seq in [&mut rgb.r, &mut rgb.g, &mut rgb.b, &mut rgb.black] {
+                // Replanned windows no longer continue the same yarn.
+                seq.linked_from = seq.linked_from.filter(|&other| !replan[other]);
+                seq.linked_to = seq.linked_to.filter(|&other| !replan[other]);
 
-                    *rgb.channel(idx) = seq;
-                    Ok::<_, eyre::Report>(())
-                })?;
+                yarn_length.fetch_add(seq.yarn_length);
+                settings.wraps.add(index, seq);
+            }
         }
+    }
 
-        {
-            let channel = &color_plan.gray;
-            let tasks = plan.windows.iter().zip(&mut lines).zip(&mut sequences);
+    // Threads spanning windows are planned on their own, after all others.
+    let spanning = plan.windows.iter().position(|window| window.spanning).filter(|&idx| replan[idx]);
+    let selected: Vec<bool> = replan
+        .iter()
+        .zip(&plan.windows)
+        .map(|(&replan, window)| replan && !window.spanning)
+        .collect();
 
+    let chains = if args.continuous {
+        plan::chains(&nails, &selected)
+    } else {
+        (0..plan.windows.len()).filter(|&idx| selected[idx]).map(|idx| vec![idx]).collect()
+    };
+
+    let groups = plan::contending(&nails, &chains);
+
+    let progress = Progress {
+        preliminary_break: &preliminary_break,
+        regions_covered: &regions_covered,
+        yarn_length: &yarn_length,
+    };
+
+    let context = Context {
+        plan: &plan,
+        nails: &nails,
+        lines: &lines,
+        chains: &chains,
+        groups: &groups,
+        settings: &settings,
+        start: args.start,
+        start_candidates: args.start_candidates,
+        progress,
+    };
+
+    let budgets_m;
+
+    if args.rgb {
+        let mut coverage = color::decouple(&image, &primary, args.unmix);
+
+        if let Some(method) = args.dither {
+            dither::dither(&mut coverage, &primary, method);
+        }
+
+        let color_plan = coverage.into_plan();
+        // FIXME: the blending mode in planning makes no sense here. We add chroma, but it
+        // does luminance planning. If some region is a mix of red/white it won't plan any
+        // red but everything else. What.
+        let channels = [&color_plan.red, &color_plan.green, &color_plan.blue, &color_plan.gray];
+        let yarns = ["red", "green", "blue", "gray"];
+
+        let kept_mm: Vec<f32> = (0..yarns.len())
+            .map(|idx| kept_length(&plan, &replan, sequences.iter_mut().map(|rgb| &*rgb.channel(idx))))
+            .collect();
+
+        let shares = args.budget.share(&plan, &yarns, &channels, &replan, &kept_mm)?;
+
+        for (idx, channel) in channels.into_iter().enumerate() {
             let class = plan::LineClass {
                 of: 4,
-                idx: 3,
+                idx,
             };
 
-            tasks
-                .par_bridge()
-                .into_par_iter()
-                .try_for_each(|((window, lines), rgb)| {
-                    let seq = plan::plan(&channel, window, lines, &class)?;
+            let previous: Vec<_> = sequences
+                .iter_mut()
+                .map(|rgb| match idx {
+                    0 => None,
+                    idx => rgb.channel(idx - 1).sequence.last().copied(),
+                })
+                .collect();
 
-                    preliminary_break
-                        .fetch_add(
-                            u32::from(matches!(seq.break_reason, plan::BreakReason::EndOfIteration)),
-                            Ordering::Relaxed,
-                        );
-                    regions_covered.fetch_add(1, Ordering::Relaxed);
-                    yarn_length.fetch_add(seq.yarn_length);
+            for (index, seq) in plan_yarn(channel, &class, &previous, &shares.windows[idx], &context)? {
+                *sequences[index].channel(idx) = seq;
+            }
 
-                    rgb.black = seq;
-                    Ok::<_, eyre::Report>(())
-                })?;
+            if let Some(darkness) = shares.darkness.get(idx) {
+                let mut laid: Vec<_> = sequences.iter_mut().map(|rgb| rgb.channel(idx)).collect();
+                spend_leftover(channel, &class, &shares.windows[idx], darkness, &mut laid, &context)?;
+            }
+
+            if let Some(index) = spanning {
+                let laid: Vec<_> = sequences.iter_mut().map(|rgb| &*rgb.channel(idx)).collect();
+                let seq = span_yarn(channel, &class, index, &laid, &context)?;
+                *sequences[index].channel(idx) = seq;
+            }
         }
 
+        budgets_m = shares.of_yarn;
     } else {
-        let image = image::DynamicImage::ImageRgb8(image).into_luma8();
+        let mut coverage = color::decouple_gray(&image, &primary);
+
+        if let Some(method) = args.dither {
+            dither::dither(&mut coverage, &primary, method);
+        }
+
+        let image = coverage.into_plan().gray;
 
-        let tasks = plan.windows.iter().zip(&mut lines).zip(&mut sequences);
         let class = plan::LineClass {
             of: 1,
             idx: 0,
         };
 
-        tasks
-            .par_bridge()
-            .into_par_iter()
-            .try_for_each(|((window, lines), rgb)| {
-                let seq = plan::plan(&image, window, lines, &class)?;
-
-                preliminary_break
-                    .fetch_add(
-                        u32::from(matches!(seq.break_reason, plan::BreakReason::EndOfIteration)),
-                        Ordering::Relaxed,
-                    );
-                regions_covered.fetch_add(1, Ordering::Relaxed);
-                yarn_length.fetch_add(seq.yarn_length);
+        let previous = vec![None; plan.windows.len()];
+
+        let kept_mm = kept_length(&plan, &replan, sequences.iter().map(|rgb| &rgb.black));
+        let shares = args.budget.share(&plan, &["gray"], &[&image], &replan, &[kept_mm])?;
+
+        for (index, seq) in plan_yarn(&image, &class, &previous, &shares.windows[0], &context)? {
+            sequences[index].black = seq;
+        }
+
+        if let Some(darkness) = shares.darkness.first() {
+            let mut laid: Vec<_> = sequences.iter_mut().map(|rgb| &mut rgb.black).collect();
+            spend_leftover(&image, &class, &shares.windows[0], darkness, &mut laid, &context)?;
+        }
+
+        budgets_m = shares.of_yarn;
 
-                rgb.black = seq;
-                    Ok::<_, eyre::Report>(())
-            })?;
+        if let Some(index) = spanning {
+            let laid: Vec<_> = sequences.iter().map(|rgb| &rgb.black).collect();
+            sequences[index].black = span_yarn(&image, &class, index, &laid, &context)?;
+        }
     }
 
     let preliminary_break = preliminary_break.load(Ordering::Relaxed);
@@ -160,39 +527,884 @@ fn main() -> Result<(), eyre::Report> {
 
     let yarn_length = yarn_length.load();
 
-    // Scale to height = 0.5m
-    let metric_yarn = yarn_length / (dimensions.1 as f32) * 50. / 100.;
-    eprintln!("Yarn: {metric_yarn:.3} m");
+    eprintln!("Yarn: {:.3} m", yarn_length / 1000.0);
 
-    debug::dump_output(
-        std::fs::File::create(args.debug_plan)?,
+    let output = args.artefacts.files()?;
+
+    let run = output::Run {
+        input_hash,
+        seed: args.seed,
+        definition: serde_json::from_slice(&definition)?,
+        image_size: [dimensions.0, dimensions.1],
+        board: output::Board {
+            width_mm: plan.board.width_mm,
+            height_mm: plan.board.height_mm,
+            nail_diameter_mm: plan.board.nail_diameter_mm,
+            color: output::css_color(&primary.board),
+        },
+        rgb: args.rgb,
+        start: args.start,
+        spanning_threads: args.spanning_threads,
+        budgets_m,
+        palette: palette(&primary),
+    };
+
+    export(&args.artefacts, dimensions, &plan, &lines, &sequences, &primary, args.rgb)?;
+
+    output.dump(
         dimensions,
         &plan,
         &lines,
         &sequences,
-        &primary,
-        args.rgb,
+        &seeds,
+        run,
     )?;
 
-    let output = output::Files {
-        section_mask_svg: "target/mask.svg".into(),
-        section_list: "target/sections.json".into(),
+    Ok(())
+}
+
+fn palette(primary: &color::PrimaryBase) -> output::Palette {
+    output::Palette {
+        red: output::css_color(&primary.red),
+        green: output::css_color(&primary.green),
+        blue: output::css_color(&primary.blue),
+        gray: output::css_color(&primary.gray),
+    }
+}
+
+fn render(args: RenderArgs) -> Result<(), eyre::Report> {
+    let saved = output::Plan::read(std::io::BufReader::new(std::fs::File::open(&args.plan)?))?;
+
+    let mut plan = match &args.circle {
+        Some(path) => poly::read(std::fs::File::open(path)?)?,
+        None => poly::read(&serde_json::to_vec(&saved.run.definition)?[..])?,
     };
 
-    output.dump(
+    // The board may have been overridden when planning.
+    plan.board.width_mm = saved.run.board.width_mm;
+    plan.board.height_mm = saved.run.board.height_mm;
+    plan.board.nail_diameter_mm = saved.run.board.nail_diameter_mm;
+
+    if let Some(count) = saved.run.spanning_threads {
+        plan.add_spanning(count);
+    }
+
+    let sequences = saved.sequences(&plan)?;
+    args.artefacts.check()?;
+    std::fs::create_dir_all(&args.artefacts.out_dir)?;
+
+    let [w, h] = saved.run.image_size;
+    let dimensions = (w, h);
+
+    let lines = all_lines(&plan, &plan.nails());
+
+    debug::dump_plan(
+        std::fs::File::create(args.artefacts.path(&args.artefacts.debug_template)?)?,
         dimensions,
         &plan,
         &lines,
-        &sequences,
+    )?;
+
+    let primary: color::PrimaryBase = plan.primaries.to_color_base(&plan.board);
+    export(&args.artefacts, dimensions, &plan, &lines, &sequences, &primary, saved.run.rgb)?;
+
+    args.artefacts.files()?.dump_sections(dimensions, &plan, &lines)
+}
+
+/// The lines each window may use.
+fn all_lines(plan: &poly::Polygons, nails: &poly::Nails) -> Vec<plan::Lines> {
+    plan.windows
+        .iter()
+        .enumerate()
+        .map(|(idx, window)| match window.spanning {
+            true => plan::spanning_lines(plan, nails, idx),
+            false => plan::permissible_lines(window, &plan.board),
+        })
+        .collect()
+}
+
+/// Write all artefacts which only depend on the finished plan.
+fn export(
+    args: &Artefacts,
+    dimensions: (u32, u32),
+    plan: &poly::Polygons,
+    lines: &[plan::Lines],
+    sequences: &[plan::RgbSequence],
+    primary: &color::PrimaryBase,
+    rgb: bool,
+) -> Result<(), eyre::Report> {
+    debug::dump_output(
+        std::fs::File::create(args.path(&args.debug_plan)?)?,
+        dimensions,
+        plan,
+        lines,
+        sequences,
+        primary,
+        rgb,
+    )?;
+
+    render::render(
+        dimensions,
+        plan,
+        sequences,
+        primary,
+        rgb,
+    ).save(args.path(&args.render)?)?;
+
+    if let Some(path) = &args.animation {
+        animate::animate(
+            &args.path(path)?,
+            dimensions,
+            plan,
+            sequences,
+            primary,
+            rgb,
+            &animate::Animation {
+                format: args.animation_format,
+                segments_per_frame: args.segments_per_frame,
+                delay_ms: args.frame_delay_ms,
+            },
+        )?;
+    }
+
+    if let Some(dir) = &args.instructions {
+        instructions::write(
+            &args.path(dir)?,
+            plan,
+            sequences,
+            primary,
+            rgb,
+            &instructions::Layout {
+                paper: args.paper,
+                checkpoint_every: args.checkpoint_every,
+            },
+        )?;
+    }
+
+    if let Some(path) = &args.gcode {
+        gcode::write(
+            std::io::BufWriter::new(std::fs::File::create(args.path(path)?)?),
+            plan,
+            sequences,
+            rgb,
+            &gcode::Machine {
+                feed: args.gcode_feed,
+                wrap_feed: args.gcode_wrap_feed,
+                wrap_radius: args.gcode_wrap_radius,
+                window_pause: args.gcode_window_pause,
+            },
+        )?;
+    }
+
+    if let Some(dir) = &args.template {
+        template::write(&args.path(dir)?, plan, args.paper)?;
+    }
+
+    bom::write(
+        std::io::BufWriter::new(std::fs::File::create(args.path(&args.bom)?)?),
+        plan,
+        sequences,
+        primary,
+        rgb,
+        &bom::Materials {
+            wrap_allowance_mm: args.wrap_allowance_mm,
+            spool_length_m: args.spool_length_m,
+            segment_seconds: args.segment_seconds,
+        },
     )?;
 
     Ok(())
 }
 
+impl BoardSize {
+    fn apply(&self, board: &mut poly::Board) {
+        if let Some(width) = self.board_width_mm {
+            board.width_mm = width;
+        }
+
+        if let Some(height) = self.board_height_mm {
+            board.height_mm = height;
+        }
+
+        if let Some(diameter) = self.nail_diameter_mm {
+            board.nail_diameter_mm = diameter;
+        }
+    }
+}
+
+impl Budget {
+    /// The budget of each yarn in every window in millimetres, and of each yarn in metres.
+    ///
+    /// Only the windows to replan get a share, of what the kept windows did not already lay of
+    /// each yarn.
+    fn share(
+        &self,
+        plan: &poly::Polygons,
+        yarns: &[&'static str],
+        images: &[&image::GrayImage],
+        replan: &[bool],
+        kept_mm: &[f32],
+    ) -> Result<Shares, eyre::Report> {
+        let own: Vec<Option<f32>> = yarns
+            .iter()
+            .map(|&yarn| match yarn {
+                "red" => self.red_m,
+                "green" => self.green_m,
+                "blue" => self.blue_m,
+                _ => self.gray_m,
+            })
+            .collect();
+
+        if self.total_m.is_none() && own.iter().all(Option::is_none) {
+            let unlimited = vec![None; plan.windows.len()];
+            return Ok(Shares {
+                windows: vec![unlimited; yarns.len()],
+                of_yarn: BTreeMap::new(),
+                darkness: vec![],
+            });
+        }
+
+        // The spanning window takes none, its threads are limited by count.
+        let darkness = images
+            .iter()
+            .map(|image| {
+                plan.windows
+                    .iter()
+                    .map(|window| match window.spanning {
+                        true => Ok(0.0),
+                        false => plan::darkness(image, window),
+                    })
+                    .collect::<Result<Vec<f32>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut metres = own.clone();
+        if let Some(total) = self.total_m {
+            let rest = (total - own.iter().flatten().sum::<f32>()).max(0.0);
+            let open: Vec<usize> = (0..yarns.len()).filter(|&idx| own[idx].is_none()).collect();
+            let weights: Vec<f32> = open.iter().map(|&idx| darkness[idx].iter().sum()).collect();
+
+            for (&idx, share) in open.iter().zip(plan::allocate(rest, &weights)) {
+                metres[idx] = Some(share);
+            }
+        }
+
+        let open: Vec<usize> = (0..plan.windows.len())
+            .filter(|&idx| replan[idx] && !plan.windows[idx].spanning)
+            .collect();
+
+        let windows = metres
+            .iter()
+            .zip(&darkness)
+            .zip(kept_mm)
+            .map(|((metres, darkness), kept)| {
+                let mut windows = vec![None; darkness.len()];
+
+                if let Some(metres) = metres {
+                    let rest = (metres * 1000.0 - kept).max(0.0);
+                    let weights: Vec<f32> = open.iter().map(|&idx| darkness[idx]).collect();
+
+                    for (&idx, share) in open.iter().zip(plan::allocate(rest, &weights)) {
+                        windows[idx] = Some(share);
+                    }
+                }
+
+                windows
+            })
+            .collect();
+
+        let of_yarn = yarns
+            .iter()
+            .zip(&metres)
+            .filter_map(|(&yarn, metres)| Some((yarn.to_string(), (*metres)?)))
+            .collect();
+
+        Ok(Shares {
+            windows,
+            of_yarn,
+            darkness,
+        })
+    }
+}
+
+impl Artefacts {
+    /// Resolve a path within the output directory.
+    fn path(&self, name: impl AsRef<std::path::Path>) -> Result<PathBuf, eyre::Report> {
+        in_out_dir(&self.out_dir, name)
+    }
+
+    /// Fail early, before planning, on any path outside the output directory.
+    fn check(&self) -> Result<(), eyre::Report> {
+        let optional = [&self.animation, &self.instructions, &self.template, &self.gcode];
+        let paths = [&self.debug_template, &self.debug_plan, &self.render, &self.bom]
+            .into_iter()
+            .chain(optional.into_iter().flatten());
+
+        for path in paths {
+            self.path(path)?;
+        }
+
+        Ok(())
+    }
+
+    fn files(&self) -> Result<output::Files, eyre::Report> {
+        Ok(output::Files {
+            section_mask_svg: self.path("mask.svg")?,
+            section_list: self.path("sections.json")?,
+        })
+    }
+}
+
+/// Resolve an output file relative to the output directory, which it must not leave.
+fn in_out_dir(
+    out_dir: &std::path::Path,
+    name: impl AsRef<std::path::Path>,
+) -> Result<PathBuf, eyre::Report> {
+    use std::path::Component;
+
+    let name = name.as_ref();
+    let is_inside = name
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+    if !is_inside {
+        eyre::bail!("{} is not within the output directory", name.display());
+    }
+
+    Ok(out_dir.join(name))
+}
+
+/// Tallies over all planned windows and yarns.
+struct Progress<'a> {
+    preliminary_break: &'a AtomicU32,
+    regions_covered: &'a AtomicU32,
+    yarn_length: &'a AtomicF32,
+}
+
+/// Everything shared by planning each yarn.
+struct Context<'a> {
+    plan: &'a poly::Polygons,
+    nails: &'a poly::Nails,
+    lines: &'a [plan::Lines],
+    chains: &'a [Vec<usize>],
+    /// Chains which are planned one after another, by index into `chains`.
+    groups: &'a [Vec<usize>],
+    settings: &'a plan::Settings,
+    start: plan::Start,
+    start_candidates: usize,
+    progress: Progress<'a>,
+}
+
+/// Plan one yarn through the windows of all chains.
+///
+/// The chains of a group are planned one after another, while groups are planned in parallel.
+fn plan_yarn(
+    image: &image::GrayImage,
+    class: &plan::LineClass,
+    previous: &[Option<plan::PolygonPoint>],
+    budgets: &[Option<f32>],
+    context: &Context,
+) -> Result<Vec<(usize, plan::Sequence)>, eyre::Report> {
+    let Context { chains, groups, .. } = context;
+
+    let planned = groups
+        .par_iter()
+        .map(|group| {
+            let mut planned = vec![];
+
+            for &chain in group {
+                planned.extend(plan_chain(image, class, previous, budgets, &chains[chain], context)?);
+            }
+
+            Ok::<_, eyre::Report>(planned)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(planned.into_iter().flatten().collect())
+}
+
+/// Plan one yarn through the windows of a chain.
+///
+/// Windows are planned in order, each continuing the yarn from the nail where the previous one
+/// ended.
+fn plan_chain(
+    image: &image::GrayImage,
+    class: &plan::LineClass,
+    previous: &[Option<plan::PolygonPoint>],
+    budgets: &[Option<f32>],
+    chain: &[usize],
+    context: &Context,
+) -> Result<Vec<(usize, plan::Sequence)>, eyre::Report> {
+    let Context { plan, nails, lines, settings, progress, .. } = context;
+
+    let mut planned: Vec<(usize, plan::Sequence)> = vec![];
+    // The nail the yarn of the previous window ended at, ready to continue.
+    let mut continue_at = None;
+
+    for (position, &index) in chain.iter().enumerate() {
+        let of_window = &nails.of_window[index];
+
+        let continued = continue_at
+            .and_then(|nail| of_window.iter().position(|&n| n == nail))
+            .map(plan::PolygonPoint);
+
+        let end_at: Vec<_> = match chain.get(position + 1) {
+            Some(&next) => of_window
+                .iter()
+                .enumerate()
+                .filter(|(_, nail)| nails.of_window[next].contains(nail))
+                .map(|(point, _)| plan::PolygonPoint(point))
+                .collect(),
+            None => vec![],
+        };
+
+        let mut ends = plan::Ends {
+            start: None,
+            continues: continued.is_some(),
+            end_at,
+            budget_mm: budgets[index],
+            extend: vec![],
+        };
+
+        let window = &plan.windows[index];
+        ends.start = match (continued, context.start) {
+            (Some(point), _) => Some(point),
+            (None, plan::Start::First) => None,
+            (None, plan::Start::PreviousColor) => previous[index],
+            (None, plan::Start::Best) => plan::best_start(
+                image,
+                index,
+                window,
+                &lines[index],
+                class,
+                settings,
+                &ends,
+                context.start_candidates,
+            )?,
+        };
+
+        let mut seq = plan::plan(image, index, window, &lines[index], class, settings, &ends)?;
+
+        let is_laid = seq.sequence.len() > 1;
+        if is_laid && continued.is_some() && seq.sequence.first() == continued.as_ref() {
+            let (previous, before) = planned.last_mut().unwrap();
+            seq.linked_from = Some(*previous);
+            before.linked_to = Some(index);
+        }
+
+        continue_at = seq.sequence
+            .last()
+            .filter(|&point| is_laid && ends.end_at.contains(point))
+            .map(|point| of_window[point.0]);
+
+        progress.preliminary_break
+            .fetch_add(
+                u32::from(matches!(seq.break_reason, plan::BreakReason::EndOfIteration)),
+                Ordering::Relaxed,
+            );
+        progress.regions_covered.fetch_add(1, Ordering::Relaxed);
+        progress.yarn_length.fetch_add(seq.yarn_length);
+
+        planned.push((index, seq));
+    }
+
+    Ok(planned)
+}
+
+/// Hand the budget some windows left unspent to those which stopped for want of it.
+///
+/// Windows stopped by their budget are extended in rounds, sharing out what is left by how much
+/// coverage they ask for. When no share is enough for another thread, the darkest of them gets
+/// all of it instead, until it can not use it either.
+fn spend_leftover(
+    image: &image::GrayImage,
+    class: &plan::LineClass,
+    budgets: &[Option<f32>],
+    darkness: &[f32],
+    laid: &mut [&mut plan::Sequence],
+    context: &Context,
+) -> Result<(), eyre::Report> {
+    let Context { plan, lines, settings, progress, .. } = context;
+
+    let budgeted: Vec<usize> = (0..laid.len()).filter(|&idx| budgets[idx].is_some()).collect();
+    let total: f32 = budgeted.iter().filter_map(|&idx| budgets[idx]).sum();
+
+    // Yarn continuing into another window must keep ending where it does.
+    let mut hungry: Vec<usize> = budgeted
+        .iter()
+        .copied()
+        .filter(|&idx| matches!(laid[idx].break_reason, plan::BreakReason::Budget))
+        .filter(|&idx| laid[idx].linked_to.is_none())
+        .collect();
+
+    // Lay more of a window's yarn, returning whether it got any longer.
+    let extend = |index: usize, extra: f32, laid: &mut [&mut plan::Sequence]| {
+        let seq = &mut *laid[index];
+        let is_laid = seq.sequence.len() > 1;
+
+        // Yarn which laid no thread yet is tied on anew.
+        let ends = plan::Ends {
+            start: seq.sequence.first().copied(),
+            continues: false,
+            end_at: vec![],
+            budget_mm: Some(seq.yarn_length + extra),
+            extend: if is_laid { seq.sequence.clone() } else { vec![] },
+        };
+
+        let mut extended = plan::plan(image, index, &plan.windows[index], &lines[index], class, settings, &ends)?;
+        let is_longer = extended.sequence.len() > seq.sequence.len().max(1);
+
+        extended.linked_from = seq.linked_from;
+        progress.yarn_length.fetch_add(extended.yarn_length - seq.yarn_length);
+        *seq = extended;
+
+        Ok::<_, eyre::Report>(is_longer)
+    };
+
+    while !hungry.is_empty() {
+        let spent: f32 = budgeted.iter().map(|&idx| laid[idx].yarn_length).sum();
+        let left = total - spent;
+
+        if left <= 0.0 {
+            break;
+        }
+
+        let weights: Vec<f32> = hungry.iter().map(|&idx| darkness[idx]).collect();
+        let mut is_longer = false;
+
+        for (&index, extra) in hungry.iter().zip(plan::allocate(left, &weights)) {
+            is_longer |= extend(index, extra, laid)?;
+        }
+
+        if !is_longer {
+            let darkest = hungry
+                .iter()
+                .copied()
+                .max_by(|&a, &b| darkness[a].total_cmp(&darkness[b]))
+                .unwrap();
+
+            if !extend(darkest, left, laid)? {
+                hungry.retain(|&idx| idx != darkest);
+            }
+        }
+
+        hungry.retain(|&idx| matches!(laid[idx].break_reason, plan::BreakReason::Budget));
+    }
+
+    Ok(())
+}
+
+/// The length of one yarn in the windows kept from a resumed plan, other than spanning ones.
+fn kept_length<'a>(
+    plan: &poly::Polygons,
+    replan: &[bool],
+    sequences: impl Iterator<Item = &'a plan::Sequence>,
+) -> f32 {
+    sequences
+        .zip(replan)
+        .zip(&plan.windows)
+        .filter(|((_, &replan), window)| !replan && !window.spanning)
+        .map(|((seq, _), _)| seq.yarn_length)
+        .sum()
+}
+
+/// Plan the threads of one yarn spanning several windows, over what is laid in them.
+fn span_yarn(
+    image: &image::GrayImage,
+    class: &plan::LineClass,
+    index: usize,
+    laid: &[&plan::Sequence],
+    context: &Context,
+) -> Result<plan::Sequence, eyre::Report> {
+    let Context { plan, lines, settings, progress, .. } = context;
+
+    let seq = plan::plan_spanning(image, plan, index, &lines[index], class, settings, laid)?;
+    progress.yarn_length.fetch_add(seq.yarn_length);
+
+    Ok(seq)
+}
+
+fn gamut(args: GamutArgs) -> Result<(), eyre::Report> {
+    let plan = poly::read({
+        std::fs::File::open(args.circle)?
+    })?;
+
+    let overlay = in_out_dir(&args.out_dir, &args.overlay)?;
+    let image = load_image(&args.image)?;
+    let primary: color::PrimaryBase = plan.primaries.to_color_base(&plan.board);
+
+    let used = if args.rgb {
+        [true; 4]
+    } else {
+        [true, false, false, false]
+    };
+
+    let gamut = gamut::analyse(&image, &primary, used, args.tolerance);
+    std::fs::create_dir_all(&args.out_dir)?;
+    gamut.overlay.save(overlay)?;
+
+    let percentage = gamut.percentage();
+    eprintln!("Out of gamut: {percentage:.2} % ({} / {} pixels)", gamut.out_of_gamut, gamut.pixels);
+
+    Ok(())
+}
+
+fn gcode_render(args: GcodeRenderArgs) -> Result<(), eyre::Report> {
+    let mut plan = poly::read({
+        std::fs::File::open(args.circle)?
+    })?;
+    args.board.apply(&mut plan.board);
+
+    let render = in_out_dir(&args.out_dir, &args.render)?;
+    let primary: color::PrimaryBase = plan.primaries.to_color_base(&plan.board);
+    let nails = plan.nails();
+
+    let runs = gcode::replay(
+        std::io::BufReader::new(std::fs::File::open(args.program)?),
+        &plan,
+        &nails,
+    )?;
+
+    let wraps: usize = runs.iter().map(|run| run.nails.len()).sum();
+    eprintln!("Replayed {} runs with {wraps} wraps", runs.len());
+
+    std::fs::create_dir_all(&args.out_dir)?;
+    gcode::render((args.width, args.height), &plan, &nails, &runs, &primary)?
+        .save(render)?;
+
+    Ok(())
+}
+
+/// Load an image as linear sRGB, honoring an embedded ICC profile.
+fn load_image(path: &std::path::Path) -> Result<image::Rgb32FImage, eyre::Report> {
+    use image::{codecs, DynamicImage, ImageDecoder, ImageFormat};
+
+    fn with_profile<'a>(
+        mut decoder: impl ImageDecoder<'a>,
+    ) -> Result<(DynamicImage, Option<Vec<u8>>), eyre::Report> {
+        let profile = decoder.icc_profile();
+        Ok((DynamicImage::from_decoder(decoder)?, profile))
+    }
+
+    let reader = image::io::Reader::new({
+        let file = std::fs::File::open(path)?;
+        std::io::BufReader::new(file)
+    });
+
+    let reader = image::io::Reader::with_guessed_format(reader)?;
+
+    let (image, profile) = match reader.format() {
+        Some(ImageFormat::Png) => with_profile(codecs::png::PngDecoder::new(reader.into_inner())?)?,
+        Some(ImageFormat::Jpeg) => with_profile(codecs::jpeg::JpegDecoder::new(reader.into_inner())?)?,
+        Some(ImageFormat::Tiff) => with_profile(codecs::tiff::TiffDecoder::new(reader.into_inner())?)?,
+        Some(ImageFormat::WebP) => with_profile(codecs::webp::WebPDecoder::new(reader.into_inner())?)?,
+        _ => (reader.decode()?, None),
+    };
+
+    // Keeps the full precision of 16-bit and float inputs, still encoded.
+    let mut image = image.into_rgb32f();
+
+    let profile = profile.map(|data| icc::Profile::parse(&data)).transpose();
+
+    match profile {
+        Ok(Some(profile)) => profile.to_linear_srgb(&mut image),
+        Ok(None) => srgb_to_linear(&mut image),
+        Err(err) => {
+            eprintln!("Ignoring ICC profile, assuming sRGB: {err}");
+            srgb_to_linear(&mut image);
+        }
+    }
+
+    Ok(image)
+}
+
+fn srgb_to_linear(image: &mut image::Rgb32FImage) {
+    for pixel in image.pixels_mut() {
+        pixel.0 = pixel.0.map(eo_transfer_f32);
+    }
+}
+
 fn eo_transfer(v: u8) -> f32 {
-    (v as f32 / 255.).powf(2.4)
+    eo_transfer_f32(v as f32 / 255.)
+}
+
+/// The sRGB electro-optical transfer function, from encoded to linear.
+fn eo_transfer_f32(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
 }
 
 fn oe_transfer(v: f32) -> u8 {
-    (v.powf(1./2.4) * 255.) as u8
+    let v = if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1./2.4) - 0.055
+    };
+
+    (v * 255.).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two square windows side by side, sharing the nails of their common edge.
+    fn two_windows() -> poly::Polygons {
+        poly::read(&br#"{
+            "kind": "polygons",
+            "windows": [
+                { "points": [[-0.8, -0.8], [0.0, -0.8], [0.0, 0.0], [0.0, 0.8], [-0.8, 0.8]], "iter_limit": 40 },
+                { "points": [[0.0, -0.8], [0.8, -0.8], [0.8, 0.8], [0.0, 0.8], [0.0, 0.0]], "iter_limit": 40 }
+            ]
+        }"#[..]).unwrap()
+    }
+
+    /// Plan the gray yarn of all windows over an even gray, spending all of any budget.
+    fn plan_gray(
+        plan: &poly::Polygons,
+        continuous: bool,
+        budgets: &[Option<f32>],
+        max_run_mm: Option<f32>,
+    ) -> Vec<plan::Sequence> {
+        let image = image::GrayImage::from_pixel(64, 64, image::Luma([128]));
+        let nails = plan.nails();
+        let lines = all_lines(plan, &nails);
+
+        let settings = plan::Settings {
+            seed: 1,
+            debug_images: None,
+            wraps: plan::Wraps::new(&nails),
+            max_run_mm,
+        };
+
+        let selected = vec![true; plan.windows.len()];
+        let chains = match continuous {
+            true => plan::chains(&nails, &selected),
+            false => (0..plan.windows.len()).map(|idx| vec![idx]).collect(),
+        };
+        let groups = plan::contending(&nails, &chains);
+
+        let preliminary_break = AtomicU32::new(0);
+        let regions_covered = AtomicU32::new(0);
+        let yarn_length = AtomicF32::new();
+
+        let context = Context {
+            plan,
+            nails: &nails,
+            lines: &lines,
+            chains: &chains,
+            groups: &groups,
+            settings: &settings,
+            start: plan::Start::First,
+            start_candidates: 1,
+            progress: Progress {
+                preliminary_break: &preliminary_break,
+                regions_covered: &regions_covered,
+                yarn_length: &yarn_length,
+            },
+        };
+
+        let class = plan::LineClass { of: 1, idx: 0 };
+        let previous = vec![None; plan.windows.len()];
+
+        let mut sequences = vec![plan::Sequence::default(); plan.windows.len()];
+        for (index, seq) in plan_yarn(&image, &class, &previous, budgets, &context).unwrap() {
+            sequences[index] = seq;
+        }
+
+        let darkness: Vec<f32> = plan.windows.iter().map(|window| plan::darkness(&image, window).unwrap()).collect();
+        let mut laid: Vec<_> = sequences.iter_mut().collect();
+        spend_leftover(&image, &class, budgets, &darkness, &mut laid, &context).unwrap();
+
+        sequences
+    }
+
+    #[test]
+    fn chained_windows_share_a_wrap() {
+        let plan = two_windows();
+        let nails = plan.nails();
+        assert_eq!(plan::chains(&nails, &[true, true]), [vec![0, 1]]);
+
+        let sequences = plan_gray(&plan, true, &[None, None], None);
+        let [first, second] = &sequences[..] else {
+            unreachable!("Two windows");
+        };
+
+        assert_eq!(first.linked_to, Some(1));
+        assert_eq!(second.linked_from, Some(0));
+        assert_eq!((first.linked_from, second.linked_to), (None, None));
+
+        // The yarn continues at the very nail where it ended.
+        let shared = nails.of_window[0][first.sequence.last().unwrap().0];
+        assert_eq!(nails.of_window[1][second.sequence[0].0], shared);
+
+        let rgb: Vec<_> = sequences
+            .iter()
+            .map(|seq| plan::RgbSequence { black: seq.clone(), ..plan::RgbSequence::default() })
+            .collect();
+        let materials = bom::Materials { wrap_allowance_mm: 0.0, spool_length_m: 0.0, segment_seconds: 0.0 };
+
+        let mut bom = vec![];
+        let primary = plan.primaries.to_color_base(&plan.board);
+        bom::write(&mut bom, &plan, &rgb, &primary, false, &materials).unwrap();
+
+        let occurrences = |window: usize, points: &[plan::PolygonPoint]| {
+            points.iter().filter(|point| nails.of_window[window][point.0] == shared).count()
+        };
+        let wraps = occurrences(0, &first.sequence) + occurrences(1, &second.sequence[1..]);
+
+        let bom = String::from_utf8(bom).unwrap();
+        assert!(bom.contains(&format!("  nail {shared}: {wraps}\n")), "{bom}");
+    }
+
+    #[test]
+    fn budget_is_never_exceeded() {
+        let plan = two_windows();
+
+        for max_run_mm in [None, Some(300.0)] {
+            for budget in [100.0, 700.0, 2500.0] {
+                let sequences = plan_gray(&plan, false, &[Some(budget), Some(budget)], max_run_mm);
+                let spent: f32 = sequences.iter().map(|seq| seq.yarn_length).sum();
+
+                assert!(spent <= 2.0 * budget, "{spent} mm laid of {budget} mm in each window");
+                // What is left is not enough for another thread, the longest is about 450 mm.
+                assert!(2.0 * budget - spent < 500.0, "{spent} mm laid of {budget} mm in each window");
+            }
+        }
+    }
+
+    #[test]
+    fn budget_is_shared_by_darkness() {
+        let plan = two_windows();
+
+        // Dark on the left, where the first window is.
+        let image = image::GrayImage::from_fn(64, 64, |x, _| image::Luma([if x < 32 { 64 } else { 192 }]));
+        let darkness: Vec<f32> = plan.windows.iter().map(|window| plan::darkness(&image, window).unwrap()).collect();
+        assert!(darkness[0] > darkness[1]);
+
+        let budget = Budget { total_m: Some(3.0), red_m: Some(1.0), green_m: None, blue_m: None, gray_m: None };
+        let shares = budget.share(&plan, &["red", "gray"], &[&image, &image], &[true, true], &[0.0, 0.0]).unwrap();
+
+        // The gray yarn gets what the red one leaves of the total.
+        assert_eq!(shares.of_yarn, BTreeMap::from([("red".to_string(), 1.0), ("gray".to_string(), 2.0)]));
+
+        for (windows, metres) in shares.windows.iter().zip([1.0, 2.0]) {
+            let [Some(first), Some(second)] = windows[..] else {
+                unreachable!("Both windows have a budget");
+            };
+
+            assert!((first + second - metres * 1000.0).abs() < 1e-2);
+            assert!((first / second - darkness[0] / darkness[1]).abs() < 1e-4);
+        }
+
+        // Resumed, only the replanned window shares what the kept one left.
+        let shares = budget.share(&plan, &["red", "gray"], &[&image, &image], &[false, true], &[400.0, 0.0]).unwrap();
+        for (windows, expected) in shares.windows.iter().zip([600.0, 2000.0]) {
+            assert!(windows[0].is_none());
+            assert!(windows[1].is_some_and(|share| (share - expected).abs() < 1e-2));
+        }
+    }
 }
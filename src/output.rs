@@ -1,30 +1,133 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::io::Write;
 use std::path::PathBuf;
 
-use serde::Serialize;
+use eyre::{bail, eyre};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    plan::Lines,
-    plan::{RgbSequence, PolygonPoint},
-    poly::Polygons,
+    plan::{self, Lines},
+    plan::{BreakReason, RgbSequence, Sequence, PolygonPoint},
+    poly::{self, Polygon, Polygons},
+    wrap::{self, Turn},
 };
 
+/// The version of the plan schema, incremented with every incompatible change.
+///
+/// Fields added with a default that describes older plans correctly need no new version. Changes:
+///
+/// - 2: yarn lengths are measured on the board, in metres.
+/// - 3: yarn lengths include the wraps around nails.
+/// - 4: yarn lengths include tying off and on again at breaks.
+/// - 5: yarn continuing from another window shares its first wrap with it.
+pub const SCHEMA_VERSION: u32 = 5;
+
 pub struct Files {
     pub section_mask_svg: PathBuf,
     pub section_list: PathBuf,
 }
 
-#[derive(Default, Serialize)]
+/// A complete plan, as written to the section list.
+#[derive(Serialize, Deserialize)]
 pub struct Plan {
-    pub red_length_in_m: f32,
-    pub nodes_red: Vec<String>,
-    pub green_length_in_m: f32,
-    pub nodes_green: Vec<String>,
-    pub blue_length_in_m: f32,
-    pub nodes_blue: Vec<String>,
-    pub gray_length_in_m: f32,
-    pub nodes_gray: Vec<String>,
+    /// Always `SCHEMA_VERSION` of the writer.
+    pub version: u32,
+    pub run: Run,
+    /// All windows, ordered by their index.
+    pub windows: Vec<Window>,
+}
+
+/// Everything that went into planning.
+#[derive(Serialize, Deserialize)]
+pub struct Run {
+    /// The FNV-1a 64-bit hash of the input image file, in hex.
+    pub input_hash: String,
+    /// Mixed into the random state of each window.
+    pub seed: u64,
+    /// The definition file the windows were generated from, verbatim.
+    pub definition: serde_json::Value,
+    /// The resolution of the input image, which planning happened at.
+    pub image_size: [u32; 2],
+    pub board: Board,
+    /// Whether all yarns were planned, or only the gray one.
+    pub rgb: bool,
+    /// How the nail each yarn starts at was chosen.
+    #[serde(default)]
+    pub start: plan::Start,
+    /// The most threads of each yarn spanning several windows, if planned with them at all.
+    #[serde(default)]
+    pub spanning_threads: Option<u32>,
+    /// The yarn budget of each yarn which had one, in metres.
+    #[serde(default)]
+    pub budgets_m: BTreeMap<String, f32>,
+    pub palette: Palette,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Board {
+    pub width_mm: f32,
+    pub height_mm: f32,
+    #[serde(default = "default_nail_diameter")]
+    pub nail_diameter_mm: f32,
+    /// As a CSS hex color.
+    pub color: String,
+}
+
+/// The yarn colors, as CSS hex colors.
+#[derive(PartialEq, Serialize, Deserialize)]
+pub struct Palette {
+    pub red: String,
+    pub green: String,
+    pub blue: String,
+    pub gray: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Window {
+    pub index: usize,
+    /// The seed this window was planned with, which differs from the run's for windows kept when
+    /// resuming. Plans without it were all planned with the run's.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// The nails around the window, in order. Sequences refer to these by index.
+    pub nails: Vec<Nail>,
+    /// Each planned yarn, in the order they are laid.
+    pub yarns: Vec<Yarn>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Nail {
+    pub name: String,
+    /// Position in the definition's coordinates, from `-1` to `1` on both axes.
+    pub position: [f32; 2],
+    /// Position on the board in millimetres from the top left.
+    pub position_mm: [f32; 2],
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Yarn {
+    /// One of `red`, `green`, `blue` or `gray`.
+    pub yarn: String,
+    pub length_in_m: f32,
+    pub break_reason: BreakReason,
+    /// The nail index where the yarn is tied on.
+    pub start: usize,
+    /// The nail indices visited after the start, in order.
+    pub steps: Vec<usize>,
+    /// How the yarn goes around the nail of each step, the last one being tied.
+    #[serde(default)]
+    pub turns: Vec<Turn>,
+    /// The names of the start and all visited nails.
+    pub nodes: Vec<String>,
+    /// Indices into `nodes` where the yarn is tied off and a new piece tied on to continue.
+    #[serde(default)]
+    pub breaks: Vec<usize>,
+    /// The window this yarn continues from without a knot, at its start.
+    #[serde(default)]
+    pub continues_from: Option<usize>,
+    /// The window this yarn continues into without a knot, at its last nail.
+    #[serde(default)]
+    pub continues_to: Option<usize>,
 }
 
 impl Files {
@@ -34,27 +137,37 @@ impl Files {
         plan: &Polygons,
         lines: &[Lines],
         sequences: &[RgbSequence],
+        seeds: &[u64],
+        run: Run,
     ) -> Result<(), eyre::Report> {
-        Self::dump_mask(
-            std::fs::File::create(&self.section_mask_svg)?,
-            (w, h),
-            plan,
-            lines,
-        )?;
-
-        let yarn_factor = 1.0 / (w as f32) * 50. / 100.;
+        self.dump_sections((w, h), plan, lines)?;
 
         Self::dump_plan(
             std::fs::File::create(&self.section_list)?,
             plan,
-            lines,
             sequences,
-            yarn_factor,
+            seeds,
+            run,
         )?;
 
         Ok(())
     }
 
+    /// Only write the mask of the sections, leaving the section list as it is.
+    pub(crate) fn dump_sections(
+        &self,
+        dimensions: (u32, u32),
+        plan: &Polygons,
+        lines: &[Lines],
+    ) -> Result<(), eyre::Report> {
+        Self::dump_mask(
+            std::fs::File::create(&self.section_mask_svg)?,
+            dimensions,
+            plan,
+            lines,
+        )
+    }
+
     fn dump_mask(
         mut into: impl Write,
         (w, h): (u32, u32),
@@ -66,7 +179,8 @@ impl Files {
         write!(into, r#"<svg viewBox="{lx} {ly} {w} {h}" xmlns="http://www.w3.org/2000/svg">"#)?;
         let (w, h) = (w / 2.0, h / 2.0);
 
-        for (idx, (window, _lines)) in plan.windows.iter().zip(lines).enumerate() {
+        let regions = plan.windows.iter().zip(lines).enumerate().filter(|(_, (window, _))| !window.spanning);
+        for (idx, (window, _lines)) in regions {
             write!(into, r#"<polygon points=""#)?;
 
             let (mut sum_x, mut sum_y) = (0.0, 0.0);
@@ -98,52 +212,186 @@ impl Files {
     fn dump_plan(
         into: impl Write,
         plan: &Polygons,
-        lines: &[Lines],
         sequences: &[RgbSequence],
-        yarn_factor: f32,
+        seeds: &[u64],
+        run: Run,
     ) -> Result<(), eyre::Report> {
-        let _ = (plan, lines, sequences);
+        let rgb = run.rgb;
+        let labeled = plan.windows.iter().zip(sequences).zip(seeds);
+        let mut windows = vec![];
 
-        let labeled = plan.windows.iter().zip(lines).zip(sequences);
-        let mut hash = HashMap::new();
+        for (index, ((window, seq), &seed)) in labeled.enumerate() {
+            let nails = window.points
+                .iter()
+                .zip(&window.names)
+                .map(|(&(x, y), name)| {
+                    let (mx, my) = plan.board.to_mm((x, y));
+                    Nail {
+                        name: name.clone(),
+                        position: [x, y],
+                        position_mm: [mx, my],
+                    }
+                })
+                .collect();
 
-        for (idx, ((window, _lines), seq)) in labeled.enumerate() {
-            let label = idx;
+            let mut yarns = vec![];
+            if rgb {
+                yarns.push(Yarn::new("red", &seq.r, window, &plan.board));
+                yarns.push(Yarn::new("green", &seq.g, window, &plan.board));
+                yarns.push(Yarn::new("blue", &seq.b, window, &plan.board));
+            }
 
-            let name_of = |idx: PolygonPoint| -> String {
-                window.names[idx.0].clone()
-            };
+            yarns.push(Yarn::new("gray", &seq.black, window, &plan.board));
 
+            windows.push(Window {
+                index,
+                seed: Some(seed),
+                nails,
+                yarns,
+            });
+        }
 
-            let mut plan = Plan::default();
-            for win in seq.r.sequence.windows(2) {
-                let &[_, index] = win.try_into().unwrap();
-                plan.nodes_red.push(name_of(index));
-            }
+        let plan = Plan {
+            version: SCHEMA_VERSION,
+            run,
+            windows,
+        };
 
-            for win in seq.g.sequence.windows(2) {
-                let &[_, index] = win.try_into().unwrap();
-                plan.nodes_green.push(name_of(index));
-            }
+        serde_json::to_writer_pretty(into, &plan)?;
+        Ok(())
+    }
+}
+
+impl Plan {
+    pub fn read(from: impl std::io::Read) -> Result<Self, eyre::Report> {
+        let plan: Plan = serde_json::from_reader(from)?;
+
+        if plan.version != SCHEMA_VERSION {
+            bail!("Plan has schema version {}, expected {SCHEMA_VERSION}", plan.version);
+        }
 
-            for win in seq.b.sequence.windows(2) {
-                let &[_, index] = win.try_into().unwrap();
-                plan.nodes_blue.push(name_of(index));
+        Ok(plan)
+    }
+
+    /// Recover the planned sequences, checking they fit the windows of a definition.
+    pub(crate) fn sequences(&self, plan: &Polygons) -> Result<Vec<RgbSequence>, eyre::Report> {
+        if self.windows.len() != plan.windows.len() {
+            bail!(
+                "Plan has {} windows but the definition has {}",
+                self.windows.len(),
+                plan.windows.len(),
+            );
+        }
+
+        let mut sequences = vec![];
+        for (window, polygon) in self.windows.iter().zip(&plan.windows) {
+            let nails = polygon.points.len();
+            if window.nails.len() != nails {
+                bail!(
+                    "Window {} has {} nails in the plan but {nails} in the definition",
+                    window.index,
+                    window.nails.len(),
+                );
             }
 
-            for win in seq.black.sequence.windows(2) {
-                let &[_, index] = win.try_into().unwrap();
-                plan.nodes_gray.push(name_of(index));
+            let mut rgb = RgbSequence::default();
+            for yarn in &window.yarns {
+                let seq = match yarn.yarn.as_str() {
+                    "red" => &mut rgb.r,
+                    "green" => &mut rgb.g,
+                    "blue" => &mut rgb.b,
+                    "gray" => &mut rgb.black,
+                    other => bail!("Window {}: unknown yarn {other}", window.index),
+                };
+
+                let sequence: Vec<_> = core::iter::once(yarn.start)
+                    .chain(yarn.steps.iter().copied())
+                    .map(|idx| {
+                        if idx < nails {
+                            Ok(PolygonPoint(idx))
+                        } else {
+                            Err(eyre!("Window {}: no nail {idx}", window.index))
+                        }
+                    })
+                    .collect::<Result<_, _>>()?;
+
+                let mut links = yarn.continues_from.into_iter().chain(yarn.continues_to);
+                if let Some(other) = links.find(|&other| other >= plan.windows.len()) {
+                    bail!("Window {}: continues with unknown window {other}", window.index);
+                }
+
+                if let Some(&idx) = yarn.breaks.iter().find(|&&idx| idx >= sequence.len()) {
+                    bail!("Window {}: break {idx} is past the end of the yarn", window.index);
+                }
+
+                *seq = Sequence {
+                    break_reason: yarn.break_reason.clone(),
+                    sequence,
+                    yarn_length: yarn.length_in_m * 1000.0,
+                    breaks: yarn.breaks.clone(),
+                    linked_from: yarn.continues_from,
+                    linked_to: yarn.continues_to,
+                    ..Sequence::default()
+                };
             }
 
-            plan.red_length_in_m = seq.r.yarn_length * yarn_factor;
-            plan.green_length_in_m = seq.g.yarn_length * yarn_factor;
-            plan.blue_length_in_m = seq.b.yarn_length * yarn_factor;
-            plan.gray_length_in_m = seq.black.yarn_length * yarn_factor;
-            hash.insert(label.to_string(), plan);
+            sequences.push(rgb);
         }
 
-        serde_json::to_writer(into, &hash)?;
-        Ok(())
+        Ok(sequences)
+    }
+}
+
+impl Yarn {
+    fn new(
+        yarn: &'static str,
+        seq: &Sequence,
+        window: &Polygon,
+        board: &poly::Board,
+    ) -> Self {
+        let name_of = |PolygonPoint(idx): PolygonPoint| -> String {
+            window.names[idx].clone()
+        };
+
+        let (start, steps) = match seq.sequence.split_first() {
+            Some((start, steps)) => (start.0, steps.iter().map(|p| p.0).collect()),
+            None => (0, vec![]),
+        };
+
+        let path = wrap::on_board(board, &window.points, seq);
+        let turns = path.turns.iter().skip(1).copied().collect();
+
+        Yarn {
+            yarn: yarn.into(),
+            length_in_m: seq.yarn_length / 1000.0,
+            break_reason: seq.break_reason.clone(),
+            start,
+            steps,
+            turns,
+            nodes: seq.sequence.iter().copied().map(name_of).collect(),
+            breaks: seq.breaks.clone(),
+            continues_from: seq.linked_from,
+            continues_to: seq.linked_to,
+        }
     }
 }
+
+fn default_nail_diameter() -> f32 {
+    poly::Board::default().nail_diameter_mm
+}
+
+/// The FNV-1a hash of some bytes, which is stable across platforms and versions.
+pub fn fnv1a(data: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in data {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    format!("{hash:016x}")
+}
+
+pub fn css_color(color: &image::Rgb<u8>) -> String {
+    let [r, g, b] = color.0;
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
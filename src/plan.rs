@@ -1,35 +1,103 @@
+use core::sync::atomic::{AtomicU32, Ordering};
 use std::ops::Range;
+use std::path::PathBuf;
 
 use image::{GenericImage, GenericImageView, GrayImage};
 use imageproc::{rect::Rect, point::Point};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 use rand_xoshiro::{
     rand_core::SeedableRng,
     rand_core::RngCore,
     Xoshiro128Plus,
 };
 
-use crate::poly::Polygon;
-use crate::{eo_transfer, oe_transfer};
+use crate::poly::{Board, Edge, Nails, Polygon, Polygons};
+use crate::wrap;
+use crate::{eo_transfer, eo_transfer_f32};
 
 pub struct LineClass {
     pub of: usize,
     pub idx: usize,
 }
 
+/// Settings shared by planning all windows.
+pub struct Settings {
+    /// Mixed into the random state of each window.
+    pub seed: u64,
+    /// Save the mask, target and result of each window as images into this directory.
+    pub debug_images: Option<PathBuf>,
+    pub wraps: Wraps,
+    /// Split each yarn into pieces no longer than this, in millimetres.
+    pub max_run_mm: Option<f32>,
+}
+
+/// The wraps on every nail, shared by planning all windows and yarns.
+///
+/// Windows sharing a nail of limited capacity are planned one after another, see `contending`, so
+/// which of them gets to use it does not depend on the timing of parallel planning.
+pub struct Wraps {
+    of_window: Vec<Vec<usize>>,
+    capacity: Vec<Option<u32>>,
+    count: Vec<AtomicU32>,
+}
+
 #[derive(Default)]
 pub struct Lines {
     pub idx_vec: Vec<PolygonPoint>,
-    /// Weights of the lines in `idx_vec` (a coverage metric).
-    pub weight_vec: Vec<f32>,
     pub ranges: Vec<Range<usize>>,
     pub iter_limit: u32,
+    /// The nails of the window on the board, in millimetres.
+    pub nails_mm: Vec<(f32, f32)>,
+    pub nail_radius_mm: f32,
+    /// The size of the board, to find the yarn in the image again.
+    pub board_mm: (f32, f32),
 }
 
 #[derive(Default, Clone)]
 pub struct Sequence {
     pub break_reason: BreakReason,
     pub sequence: Vec<PolygonPoint>,
+    /// The length of the yarn wrapped through the nails, in millimetres on the board.
     pub yarn_length: f32,
+    /// Indices into `sequence` where the yarn is tied off and a new piece tied on to continue.
+    pub breaks: Vec<usize>,
+    /// The window this yarn continues from without a knot, at its first nail.
+    pub linked_from: Option<usize>,
+    /// The window this yarn continues into without a knot, at its last nail.
+    pub linked_to: Option<usize>,
+    /// How far the window is from its target after laying this yarn, lower being better.
+    pub error: f32,
+}
+
+/// How to choose the nail a yarn starts at, unless it continues from another window.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Start {
+    /// The first nail of the window with room.
+    #[default]
+    First,
+    /// Try several nails and keep the one with the lowest error.
+    Best,
+    /// Where the previous yarn through the window ended.
+    ///
+    /// Yarns follow each other as red, green, blue, then gray, and red starts at the first nail.
+    /// Only for `--rgb`, a single gray yarn has no previous one.
+    PreviousColor,
+}
+
+/// Where the yarn through a window starts and ends.
+#[derive(Default)]
+pub struct Ends {
+    /// Start here instead of the first nail with room.
+    pub start: Option<PolygonPoint>,
+    /// The start is where the yarn of another window ended, and already took its wrap there.
+    pub continues: bool,
+    /// If not empty, end at one of these nails so the yarn can continue into the next window.
+    pub end_at: Vec<PolygonPoint>,
+    /// Stop before the yarn gets longer than this, in millimetres as `Sequence::yarn_length`.
+    pub budget_mm: Option<f32>,
+    /// If not empty, continue this yarn which is already laid, instead of starting anew.
+    pub extend: Vec<PolygonPoint>,
 }
 
 #[derive(Default, Clone)]
@@ -40,171 +108,815 @@ pub struct RgbSequence {
     pub black: Sequence,
 }
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum BreakReason {
     #[default]
     EndOfIteration,
     Covered,
     LocalOptimum,
+    /// Laying another line would have gone over the yarn budget of the window.
+    Budget,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct PolygonPoint(pub usize);
 
+/// The part of the image a yarn is planned in.
+struct Canvas {
+    /// The pixel of the image at the top left of the canvas.
+    origin: Point<i32>,
+    /// The size of the whole image.
+    dimensions: (u32, u32),
+    /// White where the yarn is planned.
+    mask: GrayImage,
+    target: GrayImage,
+    /// The coverage laid so far, white being the bare board.
+    done: GrayImage,
+}
+
 struct ImageBackground {
-    /// Total subtracted lightness which to achieve by yarn.
+    /// Total coverage which to achieve by yarn.
     darkness: f32,
 }
 
+/// Plan the yarn of one class through a window.
+///
+/// The image is a coverage target as produced by `color`, white being the bare board and black
+/// being fully covered by this yarn. Planning darkens towards full coverage regardless of the
+/// actual board and yarn colors, those only enter through the decomposition of the image.
 pub fn plan(
     image: &GrayImage,
+    window: usize,
     poly: &Polygon,
     lines: &Lines,
     class: &LineClass,
+    settings: &Settings,
+    ends: &Ends,
 ) -> Result<Sequence, eyre::Report> {
-    let (w, h) = image.dimensions();
+    let canvas = Canvas::window(image, poly)?;
+    lay(canvas, window, lines, class, settings, ends)
+}
 
-    let mut draw_points: Vec<_> = poly.points
-        .iter()
-        .map(|&(x, y)| {
-            let x = ((x / 2.0 + 0.5) * w as f32) as i32;
-            let y = ((y / 2.0 + 0.5) * h as f32) as i32;
-            Point { x, y }
+/// How much coverage the image asks for within a window, to share out yarn by.
+pub fn darkness(image: &GrayImage, poly: &Polygon) -> Result<f32, eyre::Report> {
+    let canvas = Canvas::window(image, poly)?;
+    Ok(image_background(&canvas.mask, &canvas.target).darkness)
+}
+
+impl Canvas {
+    /// The image cropped to a window, with nothing laid yet.
+    fn window(image: &GrayImage, poly: &Polygon) -> Result<Self, eyre::Report> {
+        let (w, h) = image.dimensions();
+
+        let mut draw_points: Vec<_> = poly.points
+            .iter()
+            .map(|&point| to_pixel(point, (w, h)))
+            .collect();
+
+        let bound: imageproc::rect::Rect = {
+            let mut r = [0, 0, i32::MAX, i32::MAX];
+
+            for point in &draw_points {
+                r[0] = r[0].max(point.x);
+                r[1] = r[1].max(point.y);
+                r[2] = r[2].min(point.x);
+                r[3] = r[3].min(point.y);
+            }
+
+            let w = (r[0] - r[2]) as u32;
+            let h = (r[1] - r[3]) as u32;
+            Rect::at(r[2], r[3]).of_size(w, h)
+        };
+
+        let mut mask = GrayImage::new(
+            bound.width(),
+            bound.height());
+
+        let mut target = GrayImage::new(
+            bound.width(),
+            bound.height());
+
+        target.copy_from(
+            &*image.view(
+                bound.left() as u32,
+                bound.top() as u32,
+                bound.width(),
+                bound.height()),
+            0, 0
+        )?;
+
+        for point in &mut draw_points {
+            point.x -= bound.left();
+            point.y -= bound.top();
+        }
+
+        // Lighten the area we're drawing into.
+        imageproc::drawing::draw_polygon_mut(
+            &mut mask,
+            &draw_points,
+            image::Luma([0xff]),
+        );
+
+        let mut done = GrayImage::new(
+            bound.width(),
+            bound.height());
+
+        imageproc::drawing::draw_polygon_mut(
+            &mut done,
+            &draw_points,
+            image::Luma([0xff]),
+        );
+
+        Ok(Canvas {
+            origin: Point::new(bound.left(), bound.top()),
+            dimensions: (w, h),
+            mask,
+            target,
+            done,
         })
+    }
+}
+
+/// Plan threads spanning several windows, over everything laid into them with the same yarn.
+///
+/// The window is the one added by `Polygons::add_spanning`, and `laid` holds the sequence of this
+/// yarn in every window. The yarn starts at the end of the thread along which the most coverage is
+/// missing.
+pub fn plan_spanning(
+    image: &GrayImage,
+    plan: &Polygons,
+    window: usize,
+    lines: &Lines,
+    class: &LineClass,
+    settings: &Settings,
+    laid: &[&Sequence],
+) -> Result<Sequence, eyre::Report> {
+    let dimensions = image.dimensions();
+    let mut mask = GrayImage::new(dimensions.0, dimensions.1);
+
+    for poly in plan.windows.iter().filter(|poly| !poly.spanning) {
+        let points: Vec<_> = poly.points.iter().map(|&point| to_pixel(point, dimensions)).collect();
+        imageproc::drawing::draw_polygon_mut(&mut mask, &points, image::Luma([0xff]));
+    }
+
+    // As the render lays them, around the nails.
+    let mut done = mask.clone();
+    for (poly, seq) in plan.windows.iter().zip(laid).filter(|(poly, _)| !poly.spanning) {
+        for (a, b) in wrap::on_board(&plan.board, &poly.points, seq).segments {
+            let (a, b) = (plan.board.to_definition(a), plan.board.to_definition(b));
+            darken_by_thread(&mut done, (to_pixel(a, dimensions), to_pixel(b, dimensions)));
+        }
+    }
+
+    let draw_points: Vec<_> = plan.windows[window].points
+        .iter()
+        .map(|&point| to_pixel(point, dimensions))
         .collect();
 
-    let bound: imageproc::rect::Rect = {
-        let mut r = [0, 0, i32::MAX, i32::MAX];
+    let mut best: Option<(f32, PolygonPoint)> = None;
 
-        for point in &draw_points {
-            r[0] = r[0].max(point.x);
-            r[1] = r[1].max(point.y);
-            r[2] = r[2].min(point.x);
-            r[3] = r[3].min(point.y);
+    for (from, r) in lines.ranges.iter().enumerate() {
+        let from = PolygonPoint(from);
+        if !settings.wraps.has_room(window, from) {
+            continue;
         }
 
-        let w = (r[0] - r[2]) as u32;
-        let h = (r[1] - r[3]) as u32;
-        Rect::at(r[2], r[3]).of_size(w, h)
+        let targets = lines.idx_vec[r.start..r.end]
+            .iter()
+            .filter(|&&to| settings.wraps.has_room(window, to));
+
+        for &to in targets {
+            let missing = missing_along(&mask, image, &done, draw_points[from.0], draw_points[to.0]);
+
+            if missing > best.map_or(0.0, |(best, _)| best) {
+                best = Some((missing, from));
+            }
+        }
+    }
+
+    let Some((_, start)) = best else {
+        return Ok(Sequence {
+            break_reason: BreakReason::LocalOptimum,
+            ..Sequence::default()
+        });
     };
 
-    let mut mask = GrayImage::new(
-        bound.width(),
-        bound.height());
+    let canvas = Canvas {
+        origin: Point::new(0, 0),
+        dimensions,
+        mask,
+        target: image.clone(),
+        done,
+    };
 
-    let mut target = GrayImage::new(
-        bound.width(),
-        bound.height());
+    let ends = Ends {
+        start: Some(start),
+        ..Ends::default()
+    };
 
-    target.copy_from(
-        &*image.view(
-            bound.left() as u32,
-            bound.top() as u32,
-            bound.width(),
-            bound.height()),
-        0, 0
-    )?;
+    lay(canvas, window, lines, class, settings, &ends)
+}
 
-    for point in &mut draw_points {
-        point.x -= bound.left();
-        point.y -= bound.top();
-    }
+/// How much coverage is missing along a line, compared to the target, in linear light.
+///
+/// Only samples the pixels on the line, as a quick estimate of how much a thread there helps.
+fn missing_along(
+    mask: &GrayImage,
+    target: &GrayImage,
+    done: &GrayImage,
+    a: Point<i32>,
+    b: Point<i32>,
+) -> f32 {
+    let steps = (b.x - a.x).abs().max((b.y - a.y).abs()).max(1);
+    let (w, h) = mask.dimensions();
 
-    // Lighten the area we're drawing into.
-    imageproc::drawing::draw_polygon_mut(
-        &mut mask,
-        &draw_points,
-        image::Luma([0xff]),
-    );
+    (0..=steps)
+        .map(|step| {
+            let x = a.x + (b.x - a.x) * step / steps;
+            let y = a.y + (b.y - a.y) * step / steps;
 
+            if x < 0 || y < 0 || x as u32 >= w || y as u32 >= h {
+                return 0.0;
+            }
+
+            let (x, y) = (x as u32, y as u32);
+            if *mask.get_pixel(x, y) != image::Luma([0xff]) {
+                return 0.0;
+            }
+
+            let &image::Luma([t]) = target.get_pixel(x, y);
+            let &image::Luma([d]) = done.get_pixel(x, y);
+            (eo_transfer(d) - eo_transfer(t)).max(0.0)
+        })
+        .sum()
+}
+
+/// Choose the threads of a yarn on a prepared canvas.
+fn lay(
+    canvas: Canvas,
+    window: usize,
+    lines: &Lines,
+    class: &LineClass,
+    settings: &Settings,
+    ends: &Ends,
+) -> Result<Sequence, eyre::Report> {
+    let Canvas { origin, dimensions, mask, target, mut done } = canvas;
     let analysis = image_background(&mask, &target);
 
-    let mut done = GrayImage::new(
-        bound.width(),
-        bound.height());
+    // The threads through some nails as the render lays them, in pixels of the canvas.
+    let threads_of = |sequence: &[PolygonPoint], breaks: &[usize]| -> Vec<(Point<i32>, Point<i32>)> {
+        lines.threads(sequence, breaks, dimensions)
+            .into_iter()
+            .map(|(a, b)| (a - origin, b - origin))
+            .collect()
+    };
+
+    // Which side of the last nail the yarn passes depends on the next one, so until that is
+    // chosen the thread into it is drawn as tied there. These are the threads which change with
+    // the next nail: the one into the last nail, and if given the one on to the next.
+    let last_threads = |sequence: &[PolygonPoint], next: Option<PolygonPoint>| {
+        let mut tail = sequence[sequence.len().saturating_sub(3)..].to_vec();
+        tail.extend(next);
 
-    imageproc::drawing::draw_polygon_mut(
-        &mut done,
-        &draw_points,
-        image::Luma([0xff]),
-    );
+        let threads = threads_of(&tail, &[]);
+        let changed = if next.is_some() { 2 } else { 1 };
+        threads[threads.len().saturating_sub(changed)..].to_vec()
+    };
 
-    let mut current = PolygonPoint(0);
+    let start = match ends.start {
+        _ if !ends.extend.is_empty() => ends.extend.last().copied(),
+        Some(point) if ends.continues => Some(point),
+        _ => ends.start
+            .into_iter()
+            .chain((0..lines.ranges.len()).map(PolygonPoint))
+            .find(|&point| settings.wraps.reserve(window, point)),
+    };
 
-    let mut sequence = Vec::new();
-    sequence.push(current);
+    let Some(mut current) = start else {
+        return Ok(Sequence {
+            break_reason: BreakReason::LocalOptimum,
+            ..Sequence::default()
+        });
+    };
 
-    let mut yarn_length = 0.0f32;
+    let mut covered = 0.0f32;
     let mut break_reason = BreakReason::EndOfIteration;
 
     let mut xoshiro = Xoshiro128Plus::from_seed({
         let mut seed = [0; 16];
         let deterministic = analysis.darkness.to_ne_bytes();
         seed[..4].copy_from_slice(&deterministic);
+        seed[4..12].copy_from_slice(&settings.seed.to_le_bytes());
         seed
     });
 
     let mut hit_count = vec![0; lines.ranges.len()];
+    // The canvas before any thread, to draw the finished yarn onto.
+    let bare = done.clone();
 
-    for _ in 0..poly.iter_limit {
-        if yarn_length >= analysis.darkness * 16.0 {
+    // Yarn which is extended keeps its wraps, only its threads are drawn again.
+    let mut sequence = match ends.extend.is_empty() {
+        true => vec![current],
+        false => ends.extend.clone(),
+    };
+
+    let laid = threads_of(&sequence, &[]);
+    for &thread in laid.iter().take(laid.len().saturating_sub(1)) {
+        darken_by_thread(&mut done, thread);
+    }
+
+    for pair in sequence.windows(2) {
+        hit_count[pair[1].0] += 1;
+    }
+
+    // A budget replaces the coverage rule, while the yarn is still limited to as many threads.
+    let iterations = lines.iter_limit.saturating_sub(sequence.len() as u32 - 1);
+
+    // Threads are measured untied while they are chosen, the finished yarn is checked again below.
+    let mut spent = wrap::path(&lines.nails_mm, lines.nail_radius_mm, &sequence, &[]).length;
+    let fits = |sequence: &[PolygonPoint], spent: f32, point: PolygonPoint| match ends.budget_mm {
+        Some(budget) => spent + added_length(lines, sequence, point) <= budget,
+        None => true,
+    };
+
+    let mut iteration = 0;
+    while iteration < iterations {
+        if ends.budget_mm.is_none() && covered >= analysis.darkness * 16.0 {
             break_reason = BreakReason::Covered;
             break;
         }
 
         let r = &lines.ranges[current.0];
         let threads = &lines.idx_vec[r.start..r.end];
-        let weights = &lines.weight_vec[r.start..r.end];
 
         // Determine the best-fit for the next segment.
         let best_fit = best_fit(
             &mask,
             &target,
-            &draw_points,
             current,
             threads,
-            lines,
             &done,
+            |image, next| {
+                for thread in last_threads(&sequence, next) {
+                    darken_by_thread(image, thread);
+                }
+            },
             class,
             &mut xoshiro,
             &mut hit_count,
+            |point| settings.wraps.has_room(window, point) && fits(&sequence, spent, point),
         );
 
         let Some(best_fit) = best_fit else {
-            break_reason = BreakReason::LocalOptimum;
+            // Threads which would have been laid but for the budget.
+            let over_budget = threads
+                .iter()
+                .any(|&point| settings.wraps.has_room(window, point) && !fits(&sequence, spent, point));
+
+            break_reason = match over_budget {
+                true => BreakReason::Budget,
+                false => BreakReason::LocalOptimum,
+            };
             break;
         };
 
         let target = threads[best_fit];
-        yarn_length += weights[best_fit];
 
-        darken_by_thread(&mut done, &draw_points, current, target);
+        // Windows sharing a nail of limited capacity are not planned at the same time, see
+        // `contending`, so the nail still has the room `best_fit` saw. Should it not, choose again
+        // without counting an iteration: the nail has no room from now on, so this can not repeat.
+        if !settings.wraps.reserve(window, target) {
+            continue;
+        }
+
+        // The thread into the current nail is settled now, the new one is drawn as tied.
+        let [settled @ .., (a, b)] = &last_threads(&sequence, Some(target))[..] else {
+            unreachable!("A thread on to the next nail");
+        };
+
+        if let Some(&thread) = settled.first() {
+            darken_by_thread(&mut done, thread);
+        }
+
+        covered += ((b.x - a.x) as f32).hypot((b.y - a.y) as f32);
+        spent += added_length(lines, &sequence, target);
+
         hit_count[target.0] += 1;
         sequence.push(target);
 
         current = target;
+        iteration += 1;
     }
 
-    static I: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
-    let i = I.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    if !ends.end_at.is_empty() && !ends.end_at.contains(&current) {
+        end_at(&mut sequence, window, lines, ends, settings);
+    }
+
+    let tie = |sequence: &[PolygonPoint]| {
+        let path = wrap::path(&lines.nails_mm, lines.nail_radius_mm, sequence, &[]);
+
+        match settings.max_run_mm {
+            Some(max_run) => {
+                let breaks = split_runs(sequence, &path.laid, max_run);
+                let tied = wrap::path(&lines.nails_mm, lines.nail_radius_mm, sequence, &breaks);
+                (breaks, tied.length)
+            }
+            None => (vec![], path.length),
+        }
+    };
 
-    mask.save(format!("target/{i}-mask.png"))?;
-    done.save(format!("target/{i}.png"))?;
-    target.save(format!("target/{i}-target.png"))?;
+    let (mut breaks, mut yarn_length) = tie(&sequence);
+
+    // Tying the yarn where it is split bends it differently at those nails, which can make it a
+    // little longer than measured while laying it.
+    while ends.budget_mm.is_some_and(|budget| yarn_length > budget) && sequence.len() > 1 {
+        settings.wraps.release(window, sequence.pop().unwrap());
+        (breaks, yarn_length) = tie(&sequence);
+        break_reason = BreakReason::Budget;
+    }
+
+    // The finished yarn, tied where it ends and is split.
+    done = bare;
+    for thread in threads_of(&sequence, &breaks) {
+        darken_by_thread(&mut done, thread);
+    }
+
+    let error = score_img_to_target(&mask, &target, &done);
+
+    // Yarn which lays no thread is not tied on at all.
+    if sequence.len() < 2 && !ends.continues {
+        settings.wraps.release(window, sequence[0]);
+    }
+
+    if let Some(dir) = &settings.debug_images {
+        let name = class.name();
+        mask.save(dir.join(format!("window-{window}-{name}-mask.png")))?;
+        done.save(dir.join(format!("window-{window}-{name}.png")))?;
+        target.save(dir.join(format!("window-{window}-{name}-target.png")))?;
+    }
 
     Ok(Sequence {
         break_reason,
         sequence,
         yarn_length,
+        breaks,
+        error,
+        ..Sequence::default()
     })
 }
 
+/// Try starting the yarn at each of some nails, and choose the one with the lowest error.
+///
+/// The trials take wraps from a copy of the shared counts, so only the chosen start is laid when
+/// planning the window for real. Considers all nails if `candidates` is zero.
+#[allow(clippy::too_many_arguments)]
+pub fn best_start(
+    image: &GrayImage,
+    window: usize,
+    poly: &Polygon,
+    lines: &Lines,
+    class: &LineClass,
+    settings: &Settings,
+    ends: &Ends,
+    candidates: usize,
+) -> Result<Option<PolygonPoint>, eyre::Report> {
+    let len = poly.points.len();
+    let count = if candidates == 0 { len } else { candidates.min(len) };
+
+    let mut points: Vec<PolygonPoint> = (0..count).map(|idx| PolygonPoint(idx * len / count)).collect();
+    points.dedup();
+
+    let trials = points
+        .par_iter()
+        .map(|&start| {
+            let settings = Settings {
+                seed: settings.seed,
+                debug_images: None,
+                wraps: settings.wraps.snapshot(),
+                max_run_mm: settings.max_run_mm,
+            };
+
+            let ends = Ends {
+                start: Some(start),
+                continues: false,
+                end_at: ends.end_at.clone(),
+                budget_mm: ends.budget_mm,
+                extend: vec![],
+            };
+
+            let seq = plan(image, window, poly, lines, class, &settings, &ends)?;
+            Ok::<_, eyre::Report>((start, seq))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let best = trials
+        .into_iter()
+        .filter(|(start, seq)| seq.sequence.first() == Some(start))
+        .min_by(|(_, a), (_, b)| a.error.total_cmp(&b.error))
+        .map(|(start, _)| start);
+
+    Ok(best)
+}
+
+/// How much longer a yarn gets by one more thread, wrapped around the nails.
+///
+/// Only the last few nails bend differently for it, the rest of the yarn is left out.
+fn added_length(lines: &Lines, sequence: &[PolygonPoint], target: PolygonPoint) -> f32 {
+    let tail = &sequence[sequence.len().saturating_sub(3)..];
+    let mut longer = tail.to_vec();
+    longer.push(target);
+
+    let length = |sequence: &[PolygonPoint]| {
+        wrap::path(&lines.nails_mm, lines.nail_radius_mm, sequence, &[]).length
+    };
+
+    length(&longer) - length(tail)
+}
+
+/// Share out a total between parts, by their weights.
+///
+/// Parts of no weight get nothing, unless all of them weigh nothing.
+pub fn allocate(total: f32, weights: &[f32]) -> Vec<f32> {
+    let sum: f32 = weights.iter().sum();
+
+    if sum > 0.0 {
+        weights.iter().map(|weight| total * weight / sum).collect()
+    } else {
+        weights.iter().map(|_| total / weights.len() as f32).collect()
+    }
+}
+
+impl Lines {
+    /// The threads of yarn laid through some nails, in pixels of the whole image.
+    ///
+    /// These are the tangents the render draws, see `wrap`.
+    fn threads(
+        &self,
+        sequence: &[PolygonPoint],
+        breaks: &[usize],
+        dimensions: (u32, u32),
+    ) -> Vec<(Point<i32>, Point<i32>)> {
+        let (w, h) = self.board_mm;
+        let pixel = |(x, y): (f32, f32)| to_pixel(((x / w - 0.5) * 2.0, (y / h - 0.5) * 2.0), dimensions);
+
+        wrap::path(&self.nails_mm, self.nail_radius_mm, sequence, breaks)
+            .segments
+            .into_iter()
+            .map(|(a, b)| (pixel(a), pixel(b)))
+            .collect()
+    }
+}
+
+/// Group chains which share a nail of limited capacity, by their index.
+///
+/// The chains of one group are to be planned one after another in order, so which of them gets to
+/// use a shared nail does not depend on timing. Different groups contend for no nail and can be
+/// planned in parallel.
+pub fn contending(nails: &Nails, chains: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    fn root(parent: &[usize], mut idx: usize) -> usize {
+        while parent[idx] != idx {
+            idx = parent[idx];
+        }
+
+        idx
+    }
+
+    let mut parent: Vec<usize> = (0..chains.len()).collect();
+    let mut first_user: Vec<Option<usize>> = vec![None; nails.points.len()];
+
+    for (idx, chain) in chains.iter().enumerate() {
+        let limited = chain
+            .iter()
+            .flat_map(|&window| &nails.of_window[window])
+            .filter(|&&nail| nails.capacity[nail].is_some());
+
+        for &nail in limited {
+            match first_user[nail] {
+                None => first_user[nail] = Some(idx),
+                Some(other) => {
+                    let (a, b) = (root(&parent, other), root(&parent, idx));
+                    parent[a.max(b)] = a.min(b);
+                }
+            }
+        }
+    }
+
+    let mut groups: Vec<Vec<usize>> = vec![];
+    let mut group_of = vec![None; chains.len()];
+
+    for idx in 0..chains.len() {
+        let root = root(&parent, idx);
+        let group = *group_of[root].get_or_insert_with(|| {
+            groups.push(vec![]);
+            groups.len() - 1
+        });
+
+        groups[group].push(idx);
+    }
+
+    groups
+}
+
+/// Move the end of a sequence onto one of the nails where it can continue.
+///
+/// Prefers one more line to such a nail, or otherwise cuts the sequence back to its last visit of
+/// one. Leaves the sequence as is if it never gets there.
+fn end_at(
+    sequence: &mut Vec<PolygonPoint>,
+    window: usize,
+    lines: &Lines,
+    ends: &Ends,
+    settings: &Settings,
+) {
+    let &current = sequence.last().unwrap();
+    let r = &lines.ranges[current.0];
+
+    let left = ends.budget_mm.map(|budget| {
+        budget - wrap::path(&lines.nails_mm, lines.nail_radius_mm, sequence, &[]).length
+    });
+
+    let extend = lines.idx_vec[r.start..r.end]
+        .iter()
+        .copied()
+        .filter(|point| ends.end_at.contains(point))
+        .filter(|&point| left.is_none_or(|left| added_length(lines, sequence, point) <= left))
+        .find(|&point| settings.wraps.reserve(window, point));
+
+    if let Some(point) = extend {
+        sequence.push(point);
+        return;
+    }
+
+    let Some(last) = sequence.iter().rposition(|point| ends.end_at.contains(point)) else {
+        return;
+    };
+
+    for &point in &sequence[last + 1..] {
+        settings.wraps.release(window, point);
+    }
+
+    sequence.truncate(last + 1);
+}
+
+/// Order windows into chains, in which each window shares a nail with the next one.
+///
+/// Each chain is extended by the neighbour with the fewest other neighbours left, so that those
+/// are not stranded by later choices. Chains only ever continue to a window of higher index, as
+/// windows are laid in the order of their index.
+pub fn chains(nails: &Nails, selected: &[bool]) -> Vec<Vec<usize>> {
+    let windows = nails.of_window.len();
+    let shares = |a: usize, b: usize| {
+        nails.of_window[a].iter().any(|nail| nails.of_window[b].contains(nail))
+    };
+
+    let neighbours: Vec<Vec<usize>> = (0..windows)
+        .map(|a| (0..windows).filter(|&b| b != a && selected[b] && shares(a, b)).collect())
+        .collect();
+
+    let mut used: Vec<bool> = selected.iter().map(|selected| !selected).collect();
+    let mut chains = vec![];
+
+    for first in 0..windows {
+        if used[first] {
+            continue;
+        }
+
+        used[first] = true;
+        let mut chain = vec![first];
+
+        loop {
+            let &last = chain.last().unwrap();
+            let next = neighbours[last]
+                .iter()
+                .copied()
+                .filter(|&b| b > last && !used[b])
+                .min_by_key(|&b| (neighbours[b].iter().filter(|&&c| !used[c]).count(), b));
+
+            let Some(next) = next else {
+                break;
+            };
+
+            used[next] = true;
+            chain.push(next);
+        }
+
+        chains.push(chain);
+    }
+
+    chains
+}
+
+/// Choose where to tie off a yarn so that no piece is longer than `max_run`.
+///
+/// Each piece ends within its last fifth at the nail the yarn visits least often, where the knot
+/// has the most room.
+fn split_runs(sequence: &[PolygonPoint], laid: &[f32], max_run: f32) -> Vec<usize> {
+    let mut visits = std::collections::HashMap::<usize, usize>::new();
+    for &PolygonPoint(point) in sequence {
+        *visits.entry(point).or_default() += 1;
+    }
+
+    let mut breaks = vec![];
+    let mut start = 0;
+
+    while let Some(over) = (start + 1..laid.len()).find(|&idx| laid[idx] - laid[start] > max_run) {
+        let late = (start + 1..over)
+            .filter(|&idx| laid[idx] - laid[start] >= 0.8 * max_run)
+            .min_by_key(|&idx| (visits[&sequence[idx].0], core::cmp::Reverse(idx)));
+
+        // A single segment may be longer than the whole piece, it must be laid anyways.
+        let end = late.unwrap_or(if over - 1 > start { over - 1 } else { over });
+
+        if end + 1 >= sequence.len() {
+            break;
+        }
+
+        breaks.push(end);
+        start = end;
+    }
+
+    breaks
+}
+
+impl Wraps {
+    pub fn new(nails: &Nails) -> Self {
+        Wraps {
+            of_window: nails.of_window.clone(),
+            capacity: nails.capacity.clone(),
+            count: nails.points.iter().map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    /// Count the wraps of yarn which is already laid.
+    ///
+    /// Like planning, a sequence which lays no thread takes no wrap, and one continuing from
+    /// another window shares its first wrap with that window.
+    pub fn add(&self, window: usize, seq: &Sequence) {
+        if seq.sequence.len() < 2 {
+            return;
+        }
+
+        let skip = usize::from(seq.linked_from.is_some());
+        for &PolygonPoint(point) in &seq.sequence[skip..] {
+            self.count[self.of_window[window][point]].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn has_room(&self, window: usize, PolygonPoint(point): PolygonPoint) -> bool {
+        let nail = self.of_window[window][point];
+        match self.capacity[nail] {
+            Some(capacity) => self.count[nail].load(Ordering::Relaxed) < capacity,
+            None => true,
+        }
+    }
+
+    /// An independent copy of the current counts.
+    fn snapshot(&self) -> Wraps {
+        Wraps {
+            of_window: self.of_window.clone(),
+            capacity: self.capacity.clone(),
+            count: self.count.iter().map(|count| AtomicU32::new(count.load(Ordering::Relaxed))).collect(),
+        }
+    }
+
+    /// Give back a wrap taken on a nail.
+    fn release(&self, window: usize, PolygonPoint(point): PolygonPoint) {
+        self.count[self.of_window[window][point]].fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Take one wrap on a nail, if it has room for it.
+    fn reserve(&self, window: usize, PolygonPoint(point): PolygonPoint) -> bool {
+        let nail = self.of_window[window][point];
+        let capacity = self.capacity[nail].unwrap_or(u32::MAX);
+
+        self.count[nail]
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                (count < capacity).then_some(count + 1)
+            })
+            .is_ok()
+    }
+}
+
+impl LineClass {
+    /// The yarn this class is planned for, as named in the outputs.
+    pub fn name(&self) -> &'static str {
+        match (self.of, self.idx) {
+            (4, 0) => "red",
+            (4, 1) => "green",
+            (4, 2) => "blue",
+            _ => "gray",
+        }
+    }
+}
+
 impl RgbSequence {
     pub fn channel(&mut self, idx: usize) -> &mut Sequence {
         match idx {
             0 => &mut self.r,
             1 => &mut self.g,
             2 => &mut self.b,
+            3 => &mut self.black,
             _ => unreachable!("Not called with this"),
         }
     }
@@ -231,28 +943,40 @@ fn image_background(
     }
 }
 
+/// Choose the next nail amongst `threads`, by how much closer laying yarn there gets to the target.
+///
+/// The canvas `done` holds all settled threads, `lay_to` draws the rest of the yarn onto it, with
+/// a thread on to the given next nail or ending where it is.
+#[allow(clippy::too_many_arguments)]
 fn best_fit(
     mask: &GrayImage,
     target: &GrayImage,
-    draw_points: &[Point<i32>],
     source: PolygonPoint,
     threads: &[PolygonPoint],
-    _: &Lines,
     done: &GrayImage,
+    lay_to: impl Fn(&mut GrayImage, Option<PolygonPoint>),
     class: &LineClass,
     rng: &mut Xoshiro128Plus,
     hit_count: &mut [u32],
+    has_room: impl Fn(PolygonPoint) -> bool,
 ) -> Option<usize> {
-    let pre_score = score_img_to_target(mask, target, done);
+    let mut ended = done.clone();
+    lay_to(&mut ended, None);
+    let pre_score = score_img_to_target(mask, target, &ended);
 
     let mut scores = Vec::with_capacity(threads.len());
     for (idx, &candidate) in threads.iter().enumerate() {
+        if !has_room(candidate) {
+            continue;
+        }
+
         let discourage = ((source.0 + candidate.0) % class.of) != class.idx;
 
         let mut conjecture = done.clone();
-        darken_by_thread(&mut conjecture, draw_points, source, candidate);
+        lay_to(&mut conjecture, Some(candidate));
         let score = score_img_to_target(mask, target, &conjecture);
 
+        #[allow(clippy::neg_cmp_op_on_partial_ord)]
         if !(score < pre_score) {
             continue;
         }
@@ -295,15 +1019,33 @@ fn best_fit(
     })
 }
 
+/// The pixel of an image corresponding to a point of a polygon.
+pub fn to_pixel((x, y): (f32, f32), (w, h): (u32, u32)) -> Point<i32> {
+    let x = ((x / 2.0 + 0.5) * w as f32) as i32;
+    let y = ((y / 2.0 + 0.5) * h as f32) as i32;
+    Point { x, y }
+}
+
+/// The fraction of the encoded coverage target a thread leaves, by antialiasing weight.
+fn thread_keep(weight: f32) -> f32 {
+    0.5f32.powf(weight)
+}
+
+/// The fraction of light a thread covers, by antialiasing weight.
+///
+/// This is the thread model of the planner, expressed in linear light.
+pub fn thread_coverage(weight: f32) -> f32 {
+    1.0 - eo_transfer_f32(thread_keep(weight))
+}
+
+/// Draw a thread between two pixels, in the thread model of planning.
 fn darken_by_thread(
     image: &mut GrayImage,
-    draw_points: &[Point<i32>],
-    PolygonPoint(source): PolygonPoint,
-    PolygonPoint(target): PolygonPoint,
+    (source, target): (Point<i32>, Point<i32>),
 ) {
     type P = image::Luma::<u8>;
     fn interpolate(image::Luma([right]): P, image::Luma([left]): P, left_weight: f32) -> P {
-        let w = 0.5f32.powf(left_weight);
+        let w = thread_keep(left_weight);
         let new = (right as f32 + (left - right) as f32 * w) as u8;
 
         image::Luma([new])
@@ -311,13 +1053,14 @@ fn darken_by_thread(
 
     imageproc::drawing::draw_antialiased_line_segment_mut(
         image,
-        (draw_points[source].x, draw_points[source].y),
-        (draw_points[target].x, draw_points[target].y),
+        (source.x, source.y),
+        (target.x, target.y),
         image::Luma([0x00]),
         interpolate);
 }
 
-// FIXME: try a perceptual one, in particular for regions which could be filled by ''dithering''.
+// FIXME: try a perceptual one. Regions between yarn colors can be dithered beforehand, see
+// `dither`, but the error here does not know about that.
 fn score_img_to_target(
     mask: &GrayImage,
     target: &GrayImage,
@@ -364,61 +1107,164 @@ fn score_img_to_target(
     err_sum
 }
 
+/// The lines a window may use.
+///
+/// Whether a line is allowed is decided between nail centers, the yarn is then laid along the
+/// tangents of the nails at either end, see `wrap`.
 pub fn permissible_lines(
     poly: &Polygon,
-    (w, h): (u32, u32),
+    board: &Board,
     // 0..1 fraction of rectangle required
     // partial: f32,
 ) -> Lines {
-    let (w, h) = (w as f32 / 2.0, h as f32 / 2.0);
-
     fn dot(a: (f32, f32), b: (f32, f32)) -> f32 {
         a.0 * b.0 + a.1 * b.1
     }
 
-    fn signed_area(
-        poly: &Polygon,
-        a: usize,
-        mid: usize,
-        b: usize,
-    ) -> f32 {
-        let len = poly.points.len();
-        let a = poly.points[a % len];
-        let mid = poly.points[mid % len];
-        let b = poly.points[b % len];
+    fn sub(a: (f32, f32), b: (f32, f32)) -> (f32, f32) {
+        (a.0 - b.0, a.1 - b.1)
+    }
+
+    fn cross(a: (f32, f32), b: (f32, f32)) -> f32 {
+        a.0 * b.1 - a.1 * b.0
+    }
+
+    fn unit(a: (f32, f32)) -> (f32, f32) {
+        let len = dot(a, a).sqrt().max(f32::EPSILON);
+        (a.0 / len, a.1 / len)
+    }
 
-        let sa = (a.0 - mid.0, a.1 - mid.1);
-        let sb = (b.0 - mid.0, b.1 - mid.1);
+    /// Points closer than this to a line count as on it, in the coordinates of the definition.
+    const EPSILON: f32 = 1e-5;
 
-        sa.0 * sb.1 - sa.1 * sb.0
+    /// The distance of a point from the line through `a` and `b`, signed by its side.
+    fn side(a: (f32, f32), b: (f32, f32), p: (f32, f32)) -> f32 {
+        cross(unit(sub(b, a)), sub(p, a))
     }
 
-    let mut lines = Lines::default();
-    lines.iter_limit = poly.iter_limit;
+    /// Whether a direction from one of the points goes into the window, or along its edge.
+    fn enters(points: &[(f32, f32)], orientation: f32, at: usize, d: (f32, f32)) -> bool {
+        let len = points.len();
+        let next = unit(sub(points[(at + 1) % len], points[at]));
+        let prev = unit(sub(points[(at + len - 1) % len], points[at]));
+        let d = unit(d);
+
+        let after_next = orientation * cross(next, d) >= -EPSILON;
+        let before_prev = orientation * cross(d, prev) >= -EPSILON;
+
+        if orientation * cross(next, prev) >= 0.0 {
+            after_next && before_prev
+        } else {
+            // A reflex corner, where the window takes up more than half of the turn.
+            after_next || before_prev
+        }
+    }
+
+    /// Whether the line between two points stays within the window, edges included.
+    ///
+    /// It must leave both points into the window, and not cross any edge or pass another point on
+    /// the way. This holds for concave windows as well.
+    fn visible(points: &[(f32, f32)], orientation: f32, a: usize, b: usize) -> bool {
+        let len = points.len();
+        let (pa, pb) = (points[a], points[b]);
+        let d = sub(pb, pa);
+
+        if !enters(points, orientation, a, d) || !enters(points, orientation, b, sub(pa, pb)) {
+            return false;
+        }
+
+        for edge in 0..len {
+            let next = (edge + 1) % len;
+            let (pc, pd) = (points[edge], points[next]);
+
+            if [edge, next].iter().any(|&point| point == a || point == b) {
+                continue;
+            }
+
+            let (c, d) = (side(pa, pb, pc), side(pa, pb, pd));
+            let (e, f) = (side(pc, pd, pa), side(pc, pd, pb));
+            let apart = |x: f32, y: f32| (x > EPSILON && y < -EPSILON) || (x < -EPSILON && y > EPSILON);
+
+            if apart(c, d) && apart(e, f) {
+                return false;
+            }
+        }
+
+        // Yarn can not be laid straight through another nail.
+        !(0..len)
+            .filter(|&point| point != a && point != b)
+            .any(|point| {
+                let t = dot(sub(points[point], pa), d) / dot(d, d);
+                side(pa, pb, points[point]).abs() <= EPSILON && t > 0.0 && t < 1.0
+            })
+    }
+
+    /// The smallest angle between a line and the window's edges at one of its nails.
+    fn edge_angle(points: &[(f32, f32)], at: usize, towards: usize) -> f32 {
+        let len = points.len();
+        let p = points[at];
+        let q = points[towards];
+        let line = (q.0 - p.0, q.1 - p.1);
+
+        [(at + len - 1) % len, (at + 1) % len]
+            .into_iter()
+            .map(|neighbour| {
+                let n = points[neighbour];
+                let edge = (n.0 - p.0, n.1 - p.1);
+                let cos = dot(line, edge) / (dot(line, line) * dot(edge, edge)).sqrt();
+                cos.clamp(-1.0, 1.0).acos()
+            })
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    let mut lines = Lines {
+        iter_limit: poly.iter_limit,
+        nails_mm: poly.points.iter().map(|&point| board.to_mm(point)).collect(),
+        nail_radius_mm: board.nail_diameter_mm / 2.0,
+        board_mm: (board.width_mm, board.height_mm),
+        ..Lines::default()
+    };
+
+    let segments = &poly.segments;
+    let min_angle = segments.min_edge_angle_deg.map(f32::to_radians);
 
     let len = poly.points.len();
+    // The sign of the area, by which side of each edge is inside.
+    let orientation = (0..len)
+        .map(|idx| cross(poly.points[idx], poly.points[(idx + 1) % len]))
+        .sum::<f32>()
+        .signum();
+
     for (offset, _) in poly.points.iter().enumerate() {
-        // Find those targets for which the line.
         let offset = offset + len;
         let count = poly.points.len() - 3;
         let start = lines.idx_vec.len();
 
         for candidate in (offset+1..).skip(1).take(count) {
-            let prior = candidate - 1;
-            let post = candidate + 1;
+            let (from, to) = (offset % len, candidate % len);
+            if !visible(&poly.points, orientation, from, to) {
+                continue;
+            }
+
+            let (p, q) = (lines.nails_mm[from], lines.nails_mm[to]);
+            let physical = dot((q.0 - p.0, q.1 - p.1), (q.0 - p.0, q.1 - p.1)).sqrt();
 
-            if signed_area(poly, prior, offset, candidate) > 0.0 || signed_area(poly, post, offset, candidate) < 0.0 {
+            if segments.min_length_mm.is_some_and(|min| physical < min)
+                || segments.max_length_mm.is_some_and(|max| physical > max)
+            {
                 continue;
             }
 
+            if let Some(min_angle) = min_angle {
+                let angle = edge_angle(&lines.nails_mm, from, to)
+                    .min(edge_angle(&lines.nails_mm, to, from));
 
-            let a = poly.points[candidate % len];
-            let start = poly.points[offset % len];
-            let sa = ((a.0 - start.0)*w, (a.1 - start.1)*h);
-            let length = dot(sa, sa).sqrt();
+                if angle < min_angle {
+                    continue;
+                }
+            }
 
             lines.idx_vec.push(PolygonPoint(candidate % len));
-            lines.weight_vec.push(length);
         }
 
         let end = lines.idx_vec.len();
@@ -427,3 +1273,396 @@ pub fn permissible_lines(
 
     lines
 }
+
+/// The lines of the spanning window, between nails no single window has both of.
+///
+/// A line may cross the separators between windows but none of the circles bounding them, and it
+/// must pass clear of all other nails, so the yarn can be laid straight from one nail to the other.
+/// It must also meet the `Segments` constraints of every window with a nail at either of its ends.
+pub fn spanning_lines(
+    plan: &Polygons,
+    nails: &Nails,
+    window: usize,
+) -> Lines {
+    let board = &plan.board;
+    let poly = &plan.windows[window];
+    let radius = board.nail_diameter_mm / 2.0;
+
+    fn sub(a: (f32, f32), b: (f32, f32)) -> (f32, f32) {
+        (a.0 - b.0, a.1 - b.1)
+    }
+
+    fn dot(a: (f32, f32), b: (f32, f32)) -> f32 {
+        a.0 * b.0 + a.1 * b.1
+    }
+
+    fn cross(a: (f32, f32), b: (f32, f32)) -> f32 {
+        a.0 * b.1 - a.1 * b.0
+    }
+
+    /// Whether two segments cross, not counting touching ends.
+    fn crosses((a, b): ((f32, f32), (f32, f32)), (c, d): ((f32, f32), (f32, f32))) -> bool {
+        let ab = sub(b, a);
+        let cd = sub(d, c);
+        cross(ab, sub(c, a)) * cross(ab, sub(d, a)) < 0.0
+            && cross(cd, sub(a, c)) * cross(cd, sub(b, c)) < 0.0
+    }
+
+    fn distance_to_segment(p: (f32, f32), (a, b): ((f32, f32), (f32, f32))) -> f32 {
+        let ab = sub(b, a);
+        let t = (dot(sub(p, a), ab) / dot(ab, ab)).clamp(0.0, 1.0);
+        let off = sub(p, (a.0 + ab.0 * t, a.1 + ab.1 * t));
+        dot(off, off).sqrt()
+    }
+
+    /// The smallest angle between a line and the edges of a window at one of its nails.
+    fn edge_angle(region: &[(f32, f32)], at: usize, line: (f32, f32)) -> f32 {
+        let len = region.len();
+        [(at + len - 1) % len, (at + 1) % len]
+            .into_iter()
+            .map(|neighbour| {
+                let edge = sub(region[neighbour], region[at]);
+                let cos = dot(line, edge) / (dot(line, line) * dot(edge, edge)).sqrt();
+                cos.clamp(-1.0, 1.0).acos()
+            })
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    fn contains(region: &Polygon, (x, y): (f32, f32)) -> bool {
+        let len = region.points.len();
+        (0..len)
+            .filter(|&idx| {
+                let (a, b) = (region.points[idx], region.points[(idx + 1) % len]);
+                (a.1 > y) != (b.1 > y) && x < a.0 + (y - a.1) / (b.1 - a.1) * (b.0 - a.0)
+            })
+            .count() % 2 == 1
+    }
+
+    let regions: Vec<usize> = (0..plan.windows.len())
+        .filter(|&idx| !plan.windows[idx].spanning)
+        .collect();
+
+    let barriers: Vec<_> = regions
+        .iter()
+        .flat_map(|&idx| {
+            let region = &plan.windows[idx];
+            let len = region.points.len();
+            region.edges
+                .iter()
+                .enumerate()
+                .filter(|(_, &edge)| edge != Edge::Separator)
+                .map(move |(point, _)| {
+                    (board.to_mm(region.points[point]), board.to_mm(region.points[(point + 1) % len]))
+                })
+        })
+        .collect();
+
+    let of_nail = &nails.of_window[window];
+    let mut windows_of = vec![vec![]; nails.points.len()];
+    for &idx in &regions {
+        for &nail in &nails.of_window[idx] {
+            windows_of[nail].push(idx);
+        }
+    }
+
+    let nails_mm: Vec<_> = poly.points.iter().map(|&point| board.to_mm(point)).collect();
+    let regions_mm: Vec<Vec<_>> = plan.windows
+        .iter()
+        .map(|region| region.points.iter().map(|&point| board.to_mm(point)).collect())
+        .collect();
+
+    // The constraints of each window at one end of a line, which must all hold.
+    let meets_segments = |nail: usize, line: (f32, f32)| {
+        let length = dot(line, line).sqrt();
+        windows_of[nail].iter().all(|&idx| {
+            let segments = &plan.windows[idx].segments;
+            let at = nails.of_window[idx].iter().position(|&n| n == nail).unwrap();
+
+            !segments.min_length_mm.is_some_and(|min| length < min)
+                && !segments.max_length_mm.is_some_and(|max| length > max)
+                && !segments.min_edge_angle_deg
+                    .is_some_and(|min| edge_angle(&regions_mm[idx], at, line) < min.to_radians())
+        })
+    };
+
+    let len = poly.points.len();
+    let mut allowed = vec![false; len * len];
+
+    for a in 0..len {
+        for b in a + 1..len {
+            let (na, nb) = (of_nail[a], of_nail[b]);
+            if windows_of[na].iter().any(|idx| windows_of[nb].contains(idx)) {
+                continue;
+            }
+
+            let line = (nails_mm[a], nails_mm[b]);
+            if !meets_segments(na, sub(line.1, line.0)) || !meets_segments(nb, sub(line.0, line.1)) {
+                continue;
+            }
+
+            let (pa, pb) = (poly.points[a], poly.points[b]);
+            let middle = ((pa.0 + pb.0) / 2.0, (pa.1 + pb.1) / 2.0);
+
+            if !regions.iter().any(|&idx| contains(&plan.windows[idx], middle)) {
+                continue;
+            }
+
+            let near_end = |p: (f32, f32)| {
+                [line.0, line.1].into_iter().any(|end| dot(sub(p, end), sub(p, end)).sqrt() <= radius)
+            };
+
+            // At its ends the yarn leaves the nail anyways, whatever edges meet there.
+            let blocked = barriers
+                .iter()
+                .filter(|(c, d)| !near_end(*c) && !near_end(*d))
+                .any(|&barrier| crosses(line, barrier));
+
+            let obstructed = nails_mm
+                .iter()
+                .any(|&nail| !near_end(nail) && distance_to_segment(nail, line) < radius);
+
+            allowed[a * len + b] = !blocked && !obstructed;
+            allowed[b * len + a] = !blocked && !obstructed;
+        }
+    }
+
+    let mut lines = Lines {
+        iter_limit: poly.iter_limit,
+        nails_mm,
+        nail_radius_mm: radius,
+        board_mm: (board.width_mm, board.height_mm),
+        ..Lines::default()
+    };
+
+    for from in 0..len {
+        let start = lines.idx_vec.len();
+        lines.idx_vec.extend((0..len).filter(|&to| allowed[from * len + to]).map(PolygonPoint));
+
+        let end = lines.idx_vec.len();
+        lines.ranges.push(start..end);
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::poly::Segments;
+
+    fn window(points: &[(f32, f32)]) -> Polygon {
+        Polygon {
+            points: points.to_vec(),
+            iter_limit: 10,
+            names: (0..points.len()).map(|idx| idx.to_string()).collect(),
+            ring: 0,
+            capacity: vec![None; points.len()],
+            segments: Segments::default(),
+            edges: vec![Edge::Outer; points.len()],
+            spanning: false,
+        }
+    }
+
+    fn targets(lines: &Lines, from: usize) -> Vec<usize> {
+        let mut targets: Vec<_> = lines.idx_vec[lines.ranges[from].clone()]
+            .iter()
+            .map(|&PolygonPoint(to)| to)
+            .collect();
+        targets.sort_unstable();
+        targets
+    }
+
+    #[test]
+    fn concave_notch_is_not_crossed() {
+        let poly = window(&[(-0.8, -0.8), (0.8, -0.8), (0.8, 0.0), (0.0, 0.0), (0.0, 0.8), (-0.8, 0.8)]);
+        let lines = permissible_lines(&poly, &Board::default());
+
+        // Both would run through the notch at the top right.
+        assert!(!targets(&lines, 2).contains(&4));
+        assert!(!targets(&lines, 2).contains(&5));
+        // The reflex corner itself is in sight.
+        assert!(targets(&lines, 0).contains(&3));
+    }
+
+    #[test]
+    fn no_line_through_a_nail() {
+        let poly = window(&[(-0.8, -0.8), (0.0, -0.8), (0.8, -0.8), (0.8, 0.8), (-0.8, 0.8)]);
+        let lines = permissible_lines(&poly, &Board::default());
+
+        assert_eq!(targets(&lines, 0), [3]);
+        assert_eq!(targets(&lines, 2), [4]);
+    }
+
+    #[test]
+    fn threads_are_drawn_where_the_render_draws_them() {
+        let board = Board::default();
+        let poly = window(&[(-0.8, -0.8), (0.8, -0.8), (0.8, 0.8), (-0.8, 0.8)]);
+        let lines = permissible_lines(&poly, &board);
+
+        let seq = Sequence {
+            sequence: vec![PolygonPoint(0), PolygonPoint(2), PolygonPoint(1), PolygonPoint(3)],
+            ..Sequence::default()
+        };
+
+        let expected: Vec<_> = wrap::on_board(&board, &poly.points, &seq)
+            .segments
+            .into_iter()
+            .map(|(a, b)| {
+                let (a, b) = (board.to_definition(a), board.to_definition(b));
+                (to_pixel(a, (500, 500)), to_pixel(b, (500, 500)))
+            })
+            .collect();
+
+        assert_eq!(lines.threads(&seq.sequence, &[], (500, 500)), expected);
+    }
+
+    /// Two windows of two nails each, sharing the middle nail which holds two wraps.
+    fn shared_nail() -> Nails {
+        Nails {
+            points: vec![(-0.5, 0.0), (0.0, 0.0), (0.5, 0.0)],
+            of_window: vec![vec![0, 1], vec![1, 2]],
+            capacity: vec![None, Some(2), None],
+            ring: vec![0; 3],
+        }
+    }
+
+    #[test]
+    fn shared_nail_holds_no_more_than_its_capacity() {
+        let wraps = Wraps::new(&shared_nail());
+
+        assert!(wraps.reserve(0, PolygonPoint(1)));
+        assert!(wraps.reserve(1, PolygonPoint(0)));
+
+        for window in 0..2 {
+            let shared = PolygonPoint(1 - window);
+            assert!(!wraps.has_room(window, shared));
+            assert!(!wraps.reserve(window, shared));
+        }
+
+        // Other nails are not limited.
+        for _ in 0..3 {
+            assert!(wraps.reserve(0, PolygonPoint(0)));
+        }
+    }
+
+    #[test]
+    fn released_wraps_are_returned() {
+        let wraps = Wraps::new(&shared_nail());
+
+        // A start wrap taken by one window and a thread wrapped by the other.
+        wraps.add(0, &Sequence {
+            sequence: vec![PolygonPoint(0), PolygonPoint(1)],
+            ..Sequence::default()
+        });
+        assert!(wraps.reserve(1, PolygonPoint(0)));
+        assert!(!wraps.has_room(0, PolygonPoint(1)));
+
+        wraps.release(1, PolygonPoint(0));
+        assert!(wraps.has_room(0, PolygonPoint(1)));
+        assert!(wraps.reserve(0, PolygonPoint(1)));
+        assert!(!wraps.reserve(1, PolygonPoint(0)));
+    }
+
+    #[test]
+    fn snapshot_counts_on_its_own() {
+        let wraps = Wraps::new(&shared_nail());
+        assert!(wraps.reserve(0, PolygonPoint(1)));
+
+        let snapshot = wraps.snapshot();
+        assert!(snapshot.reserve(0, PolygonPoint(1)));
+        assert!(!snapshot.has_room(1, PolygonPoint(0)));
+
+        assert!(wraps.has_room(1, PolygonPoint(0)));
+    }
+
+    #[test]
+    fn chains_sharing_a_limited_nail_contend() {
+        let nails = Nails {
+            points: vec![(-0.5, 0.0), (0.0, 0.0), (0.5, 0.0), (1.0, 0.0)],
+            of_window: vec![vec![0, 1], vec![1, 2], vec![2, 3]],
+            capacity: vec![None, Some(1), None, None],
+            ring: vec![0; 4],
+        };
+
+        // The last two windows only share a nail without a limit.
+        assert_eq!(contending(&nails, &[vec![0], vec![1], vec![2]]), [vec![0, 1], vec![2]]);
+        assert_eq!(contending(&nails, &[vec![2], vec![0], vec![1]]), [vec![0], vec![1, 2]]);
+    }
+
+    /// A regular hexagon, 400 mm across on the default board.
+    fn hexagon() -> Polygon {
+        let points: Vec<_> = (0..6)
+            .map(|idx| {
+                let angle = idx as f32 * std::f32::consts::PI / 3.0;
+                (0.8 * angle.cos(), 0.8 * angle.sin())
+            })
+            .collect();
+
+        window(&points)
+    }
+
+    #[test]
+    fn convex_window_keeps_all_lines() {
+        let lines = permissible_lines(&hexagon(), &Board::default());
+
+        // All but the neighbours, as before windows could be concave.
+        for from in 0..6 {
+            let mut expected: Vec<_> = (2..5).map(|offset| (from + offset) % 6).collect();
+            expected.sort_unstable();
+            assert_eq!(targets(&lines, from), expected);
+        }
+    }
+
+    #[test]
+    fn segments_filter_lines() {
+        let lines_with = |segments| {
+            let poly = Polygon { segments, ..hexagon() };
+            permissible_lines(&poly, &Board::default())
+        };
+
+        // Lines skipping one nail are about 346 mm long and 30° off the edges, the others 400 mm
+        // and 60°.
+        let short = lines_with(Segments { max_length_mm: Some(380.0), ..Segments::default() });
+        assert_eq!(targets(&short, 0), [2, 4]);
+
+        let long = lines_with(Segments { min_length_mm: Some(350.0), ..Segments::default() });
+        assert_eq!(targets(&long, 0), [3]);
+
+        let steep = lines_with(Segments { min_edge_angle_deg: Some(45.0), ..Segments::default() });
+        assert_eq!(targets(&steep, 0), [3]);
+        assert_eq!(targets(&steep, 1), [4]);
+    }
+
+    #[test]
+    fn split_runs_keeps_pieces_short() {
+        let mut rng = Xoshiro128Plus::from_seed([0x17; 16]);
+
+        for max_run in [50.0, 200.0, 1000.0] {
+            let sequence: Vec<_> = (0..300).map(|_| PolygonPoint(rng.next_u32() as usize % 12)).collect();
+            // Some segments are longer than a whole piece.
+            let laid: Vec<f32> = std::iter::once(0.0)
+                .chain((1..sequence.len()).map(|_| 1.0 + (rng.next_u32() % 80) as f32))
+                .scan(0.0, |sum, length| {
+                    *sum += length;
+                    Some(*sum)
+                })
+                .collect();
+
+            let breaks = split_runs(&sequence, &laid, max_run);
+
+            let ends: Vec<_> = std::iter::once(0)
+                .chain(breaks.iter().copied())
+                .chain([sequence.len() - 1])
+                .collect();
+
+            for piece in ends.windows(2) {
+                let [start, end] = [piece[0], piece[1]];
+                assert!(start < end, "Breaks {breaks:?} are not increasing");
+                assert!(
+                    end == start + 1 || laid[end] - laid[start] <= max_run,
+                    "Piece from {start} to {end} is longer than {max_run}"
+                );
+            }
+        }
+    }
+}
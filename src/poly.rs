@@ -9,7 +9,45 @@ pub enum Definition {
         circles: Vec<Circle>,
         #[serde(default)]
         primaries: Primaries,
-    }
+        #[serde(default)]
+        board: Board,
+        /// How many wraps any nail holds, unlimited by default.
+        #[serde(default)]
+        nail_capacity: Option<u32>,
+        /// Which lines are worth building at all.
+        #[serde(default)]
+        segments: Segments,
+    },
+    /// Windows of any shape, given by their nails.
+    Polygons {
+        windows: Vec<Window>,
+        #[serde(default)]
+        primaries: Primaries,
+        #[serde(default)]
+        board: Board,
+        /// How many wraps any nail holds, unlimited by default.
+        #[serde(default)]
+        nail_capacity: Option<u32>,
+        /// Which lines are worth building at all.
+        #[serde(default)]
+        segments: Segments,
+    },
+}
+
+#[derive(Deserialize)]
+pub struct Window {
+    /// The nails around the window in order, from `-1` to `1` on both axes. The window need not be
+    /// convex, but its edges must not cross.
+    pub points: Vec<[f32; 2]>,
+    /// The most lines of each yarn in a window, also when planning with a yarn budget.
+    #[serde(default = "default_iter_limit")]
+    pub iter_limit: u32,
+    /// How many wraps the nails of this window hold, instead of the global capacity.
+    #[serde(default)]
+    pub nail_capacity: Option<u32>,
+    /// Constraints on the lines of this window, instead of the global ones.
+    #[serde(default)]
+    pub segments: Segments,
 }
 
 #[derive(Deserialize)]
@@ -32,8 +70,26 @@ pub struct Circle {
     pub offset: u32,
     /// Point offset at which the windows begins, in the inner.
     pub offset_inner: u32,
+    /// The most lines of each yarn in a window, also when planning with a yarn budget.
     #[serde(default = "default_iter_limit")]
     pub iter_limit: u32,
+    /// How many wraps the nails on this circle hold, instead of the global capacity.
+    #[serde(default)]
+    pub nail_capacity: Option<u32>,
+    /// Constraints on the lines of windows within this circle, instead of the global ones.
+    #[serde(default)]
+    pub segments: Segments,
+}
+
+/// Constraints on the lines between two nails, all unconstrained by default.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct Segments {
+    /// Shorter lines between nails are not used.
+    pub min_length_mm: Option<f32>,
+    /// Longer lines between nails are not used.
+    pub max_length_mm: Option<f32>,
+    /// Lines closer to the window's edge at either of their nails are not used, in degrees.
+    pub min_edge_angle_deg: Option<f32>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -41,16 +97,65 @@ pub struct Primaries {
     yarn0: [u8; 3],
     yarn1: [u8; 3],
     yarn2: [u8; 3],
+    /// The yarn used for the gray channel, and the only yarn without `--rgb`.
+    #[serde(default = "default_gray")]
+    gray: [u8; 3],
+}
+
+/// The board onto which all yarn is drawn.
+#[derive(Deserialize, Debug)]
+pub struct Board {
+    /// The color of the bare board, which the yarn covers.
+    #[serde(default = "default_board_color")]
+    pub color: [u8; 3],
+    /// The physical width covered by the definition, in millimetres.
+    #[serde(default = "default_board_size")]
+    pub width_mm: f32,
+    /// The physical height covered by the definition, in millimetres.
+    #[serde(default = "default_board_size")]
+    pub height_mm: f32,
+    /// The diameter of the nails, which the yarn wraps around.
+    #[serde(default = "default_nail_diameter")]
+    pub nail_diameter_mm: f32,
 }
 
 fn default_iter_limit() -> u32 {
     512
 }
 
+fn default_gray() -> [u8; 3] {
+    [0x00, 0x00, 0x00]
+}
+
+fn default_board_color() -> [u8; 3] {
+    [0xff, 0xff, 0xff]
+}
+
+fn default_board_size() -> f32 {
+    500.0
+}
+
+fn default_nail_diameter() -> f32 {
+    2.0
+}
+
 #[derive(Debug)]
 pub struct Polygons {
     pub windows: Vec<Polygon>,
     pub primaries: Primaries,
+    pub board: Board,
+}
+
+/// The physical nails, where windows sharing a point share its nail.
+pub struct Nails {
+    /// The position of each distinct nail.
+    pub points: Vec<(f32, f32)>,
+    /// For each window, the nail of each of its points.
+    pub of_window: Vec<Vec<usize>>,
+    /// How many wraps each nail holds, if limited.
+    pub capacity: Vec<Option<u32>>,
+    /// The innermost ring with a window using each nail.
+    pub ring: Vec<usize>,
 }
 
 #[derive(Debug)]
@@ -58,11 +163,43 @@ pub struct Polygon {
     pub points: Vec<(f32, f32)>,
     pub iter_limit: u32,
     pub names: Vec<String>,
+    /// The index of the circle in the definition which bounds this window on the outside, or 0 for
+    /// custom windows.
+    pub ring: usize,
+    /// How many wraps each point holds, if limited.
+    pub capacity: Vec<Option<u32>>,
+    pub segments: Segments,
+    /// What lies along the edge from each point to the next one.
+    pub edges: Vec<Edge>,
+    /// Not a region of the board, but all nails for threads spanning several windows.
+    pub spanning: bool,
+}
+
+/// The kind of a window's edge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edge {
+    /// Along the circle bounding the window on the outside, or the border of a custom layout.
+    Outer,
+    /// Along the circle bounding the window on the inside.
+    Inner,
+    /// Across the ring, between two windows of the same circle, or shared by two custom windows.
+    Separator,
 }
 
 pub fn read(def: impl std::io::Read) -> Result<Polygons, eyre::Report> {
     let def: Definition = serde_json::from_reader(def)?;
-    let Definition::Circles { mut circles, primaries } = def;
+    let (mut circles, primaries, board, nail_capacity, segments) = match def {
+        Definition::Circles { circles, primaries, board, nail_capacity, segments } => {
+            (circles, primaries, board, nail_capacity, segments)
+        }
+        Definition::Polygons { windows, primaries, board, nail_capacity, segments } => {
+            return Ok(Polygons {
+                windows: custom_windows(windows, nail_capacity, &segments)?,
+                primaries,
+                board,
+            });
+        }
+    };
 
     let middle = Circle {
         radius: 0.0,
@@ -74,23 +211,33 @@ pub fn read(def: impl std::io::Read) -> Result<Polygons, eyre::Report> {
         offset: 0,
         offset_inner: 0,
         iter_limit: default_iter_limit(),
+        nail_capacity: None,
+        segments: Segments::default(),
     };
 
     circles.insert(0, middle);
 
     let mut windows = vec![];
-    for slice in circles.windows(2) {
-        let &[ref pre, ref post] = slice.try_into().unwrap();
-        append_windows(&mut windows, pre, post)?;
+    for (ring, slice) in circles.windows(2).enumerate() {
+        let [pre, post]: &[Circle; 2] = slice.try_into().unwrap();
+        append_windows(&mut windows, ring, pre, post, nail_capacity, &segments)?;
     }
 
     Ok(Polygons {
         windows,
         primaries,
+        board,
     })
 }
 
-fn append_windows(windows: &mut Vec<Polygon>, pre: &Circle, post: &Circle) -> Result<(), eyre::Report> {
+fn append_windows(
+    windows: &mut Vec<Polygon>,
+    ring: usize,
+    pre: &Circle,
+    post: &Circle,
+    nail_capacity: Option<u32>,
+    segments: &Segments,
+) -> Result<(), eyre::Report> {
     fn point_by_idx(idx: u32, c: f32, radius: f32) -> (f32, f32) {
         let angle = idx as f32 * c;
         let (s, c) = angle.sin_cos();
@@ -110,19 +257,26 @@ fn append_windows(windows: &mut Vec<Polygon>, pre: &Circle, post: &Circle) -> Re
     }
 
     fn window_idx(idx: u32, circle: &Circle, post: &Circle) -> u32 {
-        if (circle as *const _ as usize) == (post as *const _ as usize) {
+        if core::ptr::eq(circle, post) {
             post.offset + (idx * circle.points_on_circle) / post.windows
         } else {
             post.offset_inner + (idx * circle.points_on_circle) / post.windows
         }
     }
 
+    // Separators between the circles count as part of the outer one.
+    let outer_capacity = post.nail_capacity.or(nail_capacity);
+    let inner_capacity = pre.nail_capacity.or(nail_capacity);
+    let segments = post.segments.or(segments);
+
     let c_pre = 2.0 * std::f32::consts::PI / pre.points_on_circle as f32;
     let c_post = 2.0 * std::f32::consts::PI / post.points_on_circle as f32;
 
     for idx in 0..post.windows {
         let mut points = vec![];
         let mut names = vec![];
+        let mut capacity = vec![];
+        let mut edges = vec![];
 
         let outer_iter = window_idx(idx, post, post)..window_idx(idx+1, post, post);
         for (idx, o) in outer_iter.enumerate() {
@@ -141,6 +295,8 @@ fn append_windows(windows: &mut Vec<Polygon>, pre: &Circle, post: &Circle) -> Re
         points.push(point_by_idx(window_idx(idx+1, post, post), c_post, post.radius));
         let post_name = (window_idx(idx, post, post)..window_idx(idx+1, post, post)).len();
         names.push(format!("o{post_name}"));
+        capacity.resize(points.len(), outer_capacity);
+        edges.resize(points.len() - 1, Edge::Outer);
 
         {
             let a = window_idx(idx+1, post, post);
@@ -156,11 +312,15 @@ fn append_windows(windows: &mut Vec<Polygon>, pre: &Circle, post: &Circle) -> Re
             }
         }
 
+        capacity.resize(points.len(), outer_capacity);
+        // Up to the first inner point.
+        edges.resize(points.len(), Edge::Separator);
+
         let inner_iter = ((1 + window_idx(idx, pre, post))..=window_idx(idx+1, pre, post)).rev();
         for (idx, o) in inner_iter.enumerate() {
             let a = point_by_idx(o, c_pre, pre.radius);
             points.push(a);
-            names.push(format!("o{idx}"));
+            names.push(format!("i{idx}"));
 
             for mid in 1..post.split_per_segment_inner {
                 let b = point_by_idx(o - 1, c_pre, pre.radius);
@@ -173,6 +333,8 @@ fn append_windows(windows: &mut Vec<Polygon>, pre: &Circle, post: &Circle) -> Re
         points.push(point_by_idx(window_idx(idx, pre, post), c_pre, pre.radius));
         let post_name = (window_idx(idx, pre, post)..window_idx(idx+1, pre, post)).len();
         names.push(format!("i{post_name}"));
+        capacity.resize(points.len(), inner_capacity);
+        edges.resize(points.len() - 1, Edge::Inner);
 
         {
             let a = window_idx(idx, pre, post);
@@ -188,16 +350,85 @@ fn append_windows(windows: &mut Vec<Polygon>, pre: &Circle, post: &Circle) -> Re
             }
         }
 
+        capacity.resize(points.len(), outer_capacity);
+        // Back around to the first outer point.
+        edges.resize(points.len(), Edge::Separator);
+
         windows.push(Polygon {
             points,
             iter_limit: post.iter_limit,
             names,
+            ring,
+            capacity,
+            segments,
+            edges,
+            spanning: false,
         });
     }
 
     Ok(())
 }
 
+/// Windows of the `polygons` kind, as given.
+///
+/// An edge is a `Separator` only where another window has the same edge, between the same two
+/// nails. Where the nail of one window lies along the edge of another, at a T-junction, neither
+/// edge is shared and both count as `Outer`.
+fn custom_windows(
+    windows: Vec<Window>,
+    nail_capacity: Option<u32>,
+    segments: &Segments,
+) -> Result<Vec<Polygon>, eyre::Report> {
+    // As for the nails, points of different windows are only approximately equal.
+    const EPSILON: f32 = 1e-4;
+
+    let same = |a: [f32; 2], b: [f32; 2]| (a[0] - b[0]).abs() < EPSILON && (a[1] - b[1]).abs() < EPSILON;
+    let edges_of = |window: &Window| -> Vec<([f32; 2], [f32; 2])> {
+        let len = window.points.len();
+        (0..len).map(|idx| (window.points[idx], window.points[(idx + 1) % len])).collect()
+    };
+
+    let mut polygons = vec![];
+    for (idx, window) in windows.iter().enumerate() {
+        if window.points.len() < 3 {
+            eyre::bail!("Window {idx} has {} points, at least 3 are required", window.points.len());
+        }
+
+        // An edge which another window has as well, in either direction, lies between the two.
+        let edges = edges_of(window)
+            .into_iter()
+            .map(|(a, b)| {
+                let shared = windows
+                    .iter()
+                    .enumerate()
+                    .filter(|&(other, _)| other != idx)
+                    .flat_map(|(_, other)| edges_of(other))
+                    .any(|(c, d)| (same(a, c) && same(b, d)) || (same(a, d) && same(b, c)));
+
+                if shared {
+                    Edge::Separator
+                } else {
+                    Edge::Outer
+                }
+            })
+            .collect();
+
+        let count = window.points.len();
+        polygons.push(Polygon {
+            points: window.points.iter().map(|&[x, y]| (x, y)).collect(),
+            iter_limit: window.iter_limit,
+            names: (0..count).map(|point| format!("p{point}")).collect(),
+            ring: 0,
+            capacity: vec![window.nail_capacity.or(nail_capacity); count],
+            segments: window.segments.or(segments),
+            edges,
+            spanning: false,
+        });
+    }
+
+    Ok(polygons)
+}
+
 impl Default for Primaries {
     fn default() -> Self {
         Primaries {
@@ -205,16 +436,124 @@ impl Default for Primaries {
             // green: image::Rgb([0xe6, 0xff, 0xff]),
             yarn1: [0xff, 0xff, 0xff],
             yarn2: [0xd2, 0xe2, 0xef],
+            gray: default_gray(),
+        }
+    }
+}
+
+impl Default for Board {
+    fn default() -> Self {
+        Board {
+            color: default_board_color(),
+            width_mm: default_board_size(),
+            height_mm: default_board_size(),
+            nail_diameter_mm: default_nail_diameter(),
+        }
+    }
+}
+
+impl Board {
+    /// The position of a polygon point on the board, in millimetres from the top left.
+    pub fn to_mm(&self, (x, y): (f32, f32)) -> (f32, f32) {
+        ((x / 2.0 + 0.5) * self.width_mm, (y / 2.0 + 0.5) * self.height_mm)
+    }
+
+    /// The inverse of `to_mm`.
+    pub fn to_definition(&self, (x, y): (f32, f32)) -> (f32, f32) {
+        ((x / self.width_mm - 0.5) * 2.0, (y / self.height_mm - 0.5) * 2.0)
+    }
+}
+
+impl Segments {
+    /// Each constraint of this, or the other's where unset.
+    pub fn or(&self, other: &Segments) -> Segments {
+        Segments {
+            min_length_mm: self.min_length_mm.or(other.min_length_mm),
+            max_length_mm: self.max_length_mm.or(other.max_length_mm),
+            min_edge_angle_deg: self.min_edge_angle_deg.or(other.min_edge_angle_deg),
+        }
+    }
+}
+
+impl Polygons {
+    /// Add a window with all nails of the board, for threads spanning several windows.
+    ///
+    /// Its lines are not bounded by any one window, see `plan::spanning_lines`, and at most
+    /// `iter_limit` of them are laid for each yarn. Its points are the nails in their order on the
+    /// template, named by that number.
+    pub fn add_spanning(&mut self, iter_limit: u32) {
+        let nails = self.nails();
+        let count = nails.points.len();
+
+        self.windows.push(Polygon {
+            points: nails.points,
+            iter_limit,
+            names: (0..count).map(|idx| idx.to_string()).collect(),
+            ring: 0,
+            capacity: nails.capacity,
+            segments: Segments::default(),
+            edges: vec![],
+            spanning: true,
+        });
+    }
+
+    /// Identify the points of all windows which are the same nail.
+    pub fn nails(&self) -> Nails {
+        // Points are computed independently for each window, so only approximately equal.
+        const EPSILON: f32 = 1e-4;
+
+        let mut points: Vec<(f32, f32)> = vec![];
+        let mut ring = vec![];
+        let mut capacity: Vec<Option<u32>> = vec![];
+        let mut of_window = vec![];
+
+        for window in &self.windows {
+            let mut nails = vec![];
+
+            for (&(x, y), &limit) in window.points.iter().zip(&window.capacity) {
+                let existing = points
+                    .iter()
+                    .position(|&(nx, ny)| (nx - x).abs() < EPSILON && (ny - y).abs() < EPSILON);
+
+                let nail = match existing {
+                    Some(nail) => nail,
+                    None => {
+                        points.push((x, y));
+                        ring.push(window.ring);
+                        capacity.push(None);
+                        points.len() - 1
+                    }
+                };
+
+                // The nail holds no more than any window claims.
+                capacity[nail] = match (capacity[nail], limit) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+
+                nails.push(nail);
+            }
+
+            of_window.push(nails);
+        }
+
+        Nails {
+            points,
+            of_window,
+            capacity,
+            ring,
         }
     }
 }
 
 impl Primaries {
-    pub fn to_color_base(&self) -> color::PrimaryBase {
+    pub fn to_color_base(&self, board: &Board) -> color::PrimaryBase {
         color::PrimaryBase {
             red: image::Rgb(self.yarn0),
             green: image::Rgb(self.yarn1),
             blue: image::Rgb(self.yarn2),
+            gray: image::Rgb(self.gray),
+            board: image::Rgb(board.color),
         }
     }
 }
@@ -0,0 +1,130 @@
+//! Simulate what the finished board looks like.
+use image::{Rgb32FImage, RgbImage};
+
+use crate::color::PrimaryBase;
+use crate::plan::{self, RgbSequence, Sequence};
+use crate::poly::{Board, Polygons};
+use crate::wrap;
+use crate::{eo_transfer, oe_transfer};
+
+/// The color of the nail heads.
+const NAIL: image::Rgb<u8> = image::Rgb([0xa0, 0xa0, 0xa0]);
+
+/// A board with threads on it, in linear light.
+#[derive(Clone)]
+pub struct Canvas {
+    image: Rgb32FImage,
+}
+
+impl Canvas {
+    /// A bare board of the given resolution.
+    pub fn new((w, h): (u32, u32), primary: &PrimaryBase) -> Self {
+        let board = image::Rgb(primary.board.0.map(eo_transfer));
+
+        Canvas {
+            image: Rgb32FImage::from_pixel(w, h, board),
+        }
+    }
+
+    /// Lay one thread of yarn between two pixels.
+    ///
+    /// This uses the same thread model as planning, but blends towards the actual yarn color.
+    pub fn thread(
+        &mut self,
+        source: imageproc::point::Point<i32>,
+        target: imageproc::point::Point<i32>,
+        yarn: image::Rgb<u8>,
+    ) {
+        type P = image::Rgb::<f32>;
+        fn blend(image::Rgb(yarn): P, image::Rgb(under): P, weight: f32) -> P {
+            let coverage = plan::thread_coverage(weight);
+            image::Rgb(core::array::from_fn(|i| under[i] + (yarn[i] - under[i]) * coverage))
+        }
+
+        imageproc::drawing::draw_antialiased_line_segment_mut(
+            &mut self.image,
+            (source.x, source.y),
+            (target.x, target.y),
+            image::Rgb(yarn.0.map(eo_transfer)),
+            blend);
+    }
+
+    /// Lay all threads of a sequence through a window, wrapped around its nails.
+    pub fn sequence(
+        &mut self,
+        board: &Board,
+        points: &[(f32, f32)],
+        seq: &Sequence,
+        yarn: image::Rgb<u8>,
+    ) {
+        for &segment in &wrap::on_board(board, points, seq).segments {
+            self.segment(board, segment, yarn);
+        }
+    }
+
+    /// Lay one straight piece of yarn, between two points on the board in millimetres.
+    pub fn segment(
+        &mut self,
+        board: &Board,
+        (source, target): ((f32, f32), (f32, f32)),
+        yarn: image::Rgb<u8>,
+    ) {
+        let dimensions = self.image.dimensions();
+        self.thread(
+            plan::to_pixel(board.to_definition(source), dimensions),
+            plan::to_pixel(board.to_definition(target), dimensions),
+            yarn,
+        );
+    }
+
+    /// Draw the heads of all nails, on top of the yarn.
+    pub fn nails(&mut self, plan: &Polygons) {
+        let dimensions = self.image.dimensions();
+        let radius = (dimensions.0.min(dimensions.1) / 500).max(1) as i32;
+        let nail = image::Rgb(NAIL.0.map(eo_transfer));
+
+        for window in &plan.windows {
+            for &point in &window.points {
+                let center = plan::to_pixel(point, dimensions);
+                imageproc::drawing::draw_filled_circle_mut(
+                    &mut self.image,
+                    (center.x, center.y),
+                    radius,
+                    nail,
+                );
+            }
+        }
+    }
+
+    pub fn to_rgb8(&self) -> RgbImage {
+        let (w, h) = self.image.dimensions();
+        RgbImage::from_fn(w, h, |x, y| {
+            let image::Rgb(c) = *self.image.get_pixel(x, y);
+            image::Rgb(c.map(|v| oe_transfer(v.clamp(0.0, 1.0))))
+        })
+    }
+}
+
+/// Render all windows, laying the yarns in the order they are planned.
+pub fn render(
+    dimensions: (u32, u32),
+    plan: &Polygons,
+    sequences: &[RgbSequence],
+    primary: &PrimaryBase,
+    is_rgbish: bool,
+) -> RgbImage {
+    let mut canvas = Canvas::new(dimensions, primary);
+
+    for (window, rgb) in plan.windows.iter().zip(sequences) {
+        if is_rgbish {
+            canvas.sequence(&plan.board, &window.points, &rgb.r, primary.red);
+            canvas.sequence(&plan.board, &window.points, &rgb.g, primary.green);
+            canvas.sequence(&plan.board, &window.points, &rgb.b, primary.blue);
+        }
+
+        canvas.sequence(&plan.board, &window.points, &rgb.black, primary.gray);
+    }
+
+    canvas.nails(plan);
+    canvas.to_rgb8()
+}
@@ -0,0 +1,188 @@
+//! A 1:1 template of all nail positions, to drill from.
+use std::io::Write;
+use std::path::Path;
+
+use crate::instructions::Paper;
+use crate::poly::{Nails, Polygons};
+
+/// Margin of each printed tile, in millimetres.
+const MARGIN: f32 = 10.0;
+/// How much neighbouring tiles overlap, so that alignment marks are on both.
+const OVERLAP: f32 = 10.0;
+/// Radius of the drilling cross hair at each nail.
+const MARK: f32 = 1.5;
+const FONT: f32 = 2.5;
+
+/// Write `template.svg`, `template.dxf` and the printable `tile-*.svg` pages into a directory.
+pub fn write(
+    dir: &Path,
+    plan: &Polygons,
+    paper: Paper,
+) -> Result<(), eyre::Report> {
+    let nails = plan.nails();
+
+    std::fs::create_dir_all(dir)?;
+
+    let (w, h) = (plan.board.width_mm, plan.board.height_mm);
+    write_svg(
+        std::io::BufWriter::new(std::fs::File::create(dir.join("template.svg"))?),
+        plan,
+        &nails,
+        (w, h),
+        (0.0, 0.0, w, h),
+        None,
+    )?;
+
+    write_dxf(
+        std::io::BufWriter::new(std::fs::File::create(dir.join("template.dxf"))?),
+        plan,
+        &nails,
+    )?;
+
+    let paper = paper.size();
+    let printable = (paper.0 - 2.0 * MARGIN, paper.1 - 2.0 * MARGIN);
+    let step = (printable.0 - OVERLAP, printable.1 - OVERLAP);
+
+    let columns = ((w - OVERLAP) / step.0).ceil().max(1.0) as usize;
+    let rows = ((h - OVERLAP) / step.1).ceil().max(1.0) as usize;
+
+    for row in 0..rows {
+        for column in 0..columns {
+            let x0 = column as f32 * step.0;
+            let y0 = row as f32 * step.1;
+
+            let tile = Tile {
+                row,
+                column,
+                rows,
+                columns,
+                step,
+            };
+
+            write_svg(
+                std::io::BufWriter::new(std::fs::File::create(dir.join(format!("tile-r{row}-c{column}.svg")))?),
+                plan,
+                &nails,
+                paper,
+                (x0, y0, printable.0, printable.1),
+                Some(&tile),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+struct Tile {
+    row: usize,
+    column: usize,
+    rows: usize,
+    columns: usize,
+    step: (f32, f32),
+}
+
+/// Draw a region of the board, in millimetres, onto a page.
+///
+/// Without a tile the page is the board itself, otherwise the region is offset by the margin of
+/// the paper and the tile's alignment marks are added.
+fn write_svg(
+    mut into: impl Write,
+    plan: &Polygons,
+    nails: &Nails,
+    (page_w, page_h): (f32, f32),
+    (x0, y0, region_w, region_h): (f32, f32, f32, f32),
+    tile: Option<&Tile>,
+) -> Result<(), eyre::Report> {
+    let offset = if tile.is_some() { MARGIN } else { 0.0 };
+    let (dx, dy) = (offset - x0, offset - y0);
+
+    write!(into, r#"<svg width="{page_w}mm" height="{page_h}mm" viewBox="0 0 {page_w} {page_h}" xmlns="http://www.w3.org/2000/svg" font-family="sans-serif" font-size="{FONT}">"#)?;
+    write!(into, r#"<clipPath id="region"><rect x="{offset}" y="{offset}" width="{region_w}" height="{region_h}" /></clipPath>"#)?;
+    write!(into, r#"<g clip-path="url(#region)"><g transform="translate({dx} {dy})">"#)?;
+
+    let (w, h) = (plan.board.width_mm, plan.board.height_mm);
+    write!(into, r#"<rect x="0" y="0" width="{w}" height="{h}" fill="none" stroke="black" stroke-width="0.2" />"#)?;
+
+    for (idx, window) in plan.windows.iter().enumerate().filter(|(_, window)| !window.spanning) {
+        write!(into, r#"<polygon points=""#)?;
+
+        let (mut sum_x, mut sum_y) = (0.0, 0.0);
+        for &point in &window.points {
+            let (x, y) = plan.board.to_mm(point);
+            sum_x += x;
+            sum_y += y;
+            write!(into, "{x},{y} ")?;
+        }
+
+        write!(into, r#"" fill="none" stroke="gray" stroke-width="0.1" />"#)?;
+
+        if !window.points.is_empty() {
+            let avg_x = sum_x / window.points.len() as f32;
+            let avg_y = sum_y / window.points.len() as f32;
+            write!(into, r#"<text text-anchor="middle" x="{avg_x}" y="{avg_y}" fill="gray">{idx}</text>"#)?;
+        }
+    }
+
+    for (idx, &point) in nails.points.iter().enumerate() {
+        let (x, y) = plan.board.to_mm(point);
+        write!(into, r#"<circle cx="{x}" cy="{y}" r="{}" fill="none" stroke="black" stroke-width="0.1" />"#, MARK / 2.0)?;
+        write!(into, r#"<path d="M {} {y} H {} M {x} {} V {}" stroke="black" stroke-width="0.1" />"#, x - MARK, x + MARK, y - MARK, y + MARK)?;
+        write!(into, r#"<text x="{x}" y="{y}" dx="{MARK}" dy="{}">{idx}</text>"#, -MARK)?;
+    }
+
+    write!(into, r#"</g></g>"#)?;
+
+    if let Some(tile) = tile {
+        // Alignment marks on every corner of the tile grid within the region. Neighbouring tiles
+        // overlap, so each mark is printed on all pages sharing it.
+        for row in 0..=tile.rows {
+            for column in 0..=tile.columns {
+                let (bx, by) = (column as f32 * tile.step.0, row as f32 * tile.step.1);
+                let (px, py) = (bx + dx, by + dy);
+
+                let inside = px >= offset - 0.01 && px <= offset + region_w + 0.01
+                    && py >= offset - 0.01 && py <= offset + region_h + 0.01;
+
+                if !inside {
+                    continue;
+                }
+
+                write!(into, r#"<path d="M {} {py} H {} M {px} {} V {}" stroke="red" stroke-width="0.2" />"#, px - 5.0, px + 5.0, py - 5.0, py + 5.0)?;
+                write!(into, r#"<circle cx="{px}" cy="{py}" r="2" fill="none" stroke="red" stroke-width="0.2" />"#)?;
+            }
+        }
+
+        let label_y = page_h - MARGIN / 2.0;
+        write!(
+            into,
+            r#"<text x="{MARGIN}" y="{label_y}">Row {} / {}, column {} / {}. Print at 100%, align red marks.</text>"#,
+            tile.row + 1, tile.rows, tile.column + 1, tile.columns,
+        )?;
+    }
+
+    write!(into, r#"</svg>"#)?;
+    Ok(())
+}
+
+/// An R12 ASCII DXF with a circle and label for every nail, in millimetres.
+fn write_dxf(
+    mut into: impl Write,
+    plan: &Polygons,
+    nails: &Nails,
+) -> Result<(), eyre::Report> {
+    let h = plan.board.height_mm;
+
+    write!(into, "0\nSECTION\n2\nENTITIES\n")?;
+
+    for (idx, &point) in nails.points.iter().enumerate() {
+        let (x, y) = plan.board.to_mm(point);
+        // DXF has its y axis pointing up.
+        let y = h - y;
+
+        write!(into, "0\nCIRCLE\n8\nNAILS\n10\n{x}\n20\n{y}\n30\n0.0\n40\n{}\n", MARK / 2.0)?;
+        write!(into, "0\nTEXT\n8\nLABELS\n10\n{}\n20\n{}\n30\n0.0\n40\n{FONT}\n1\n{idx}\n", x + MARK, y + MARK)?;
+    }
+
+    write!(into, "0\nENDSEC\n0\nEOF\n")?;
+    Ok(())
+}
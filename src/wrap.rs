@@ -0,0 +1,238 @@
+//! How yarn wraps around nails of a finite diameter.
+//!
+//! The yarn turns around every nail it visits in the direction it bends there, so it runs along
+//! the outside of the bend. Between two nails it follows a tangent of both: the outer one when
+//! both are wrapped the same way, the crossing one otherwise. The ends of a yarn, and of each piece
+//! where it is tied off and on again, are tied to the nail rather than wrapped, and leave from its
+//! center.
+//!
+//! The planner draws its threads along these tangents as well, so it scores what the render shows.
+//! Only which lines a window allows is decided between nail centers.
+//!
+//! All computations are in millimetres on the board, with the y axis pointing down. Directions
+//! are named as seen on the board, which is also how they appear in the image.
+use serde::{Deserialize, Serialize};
+
+use crate::plan::{PolygonPoint, Sequence};
+use crate::poly::Board;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Turn {
+    #[serde(rename = "cw")]
+    Clockwise,
+    #[serde(rename = "ccw")]
+    CounterClockwise,
+    /// The yarn is tied to the nail, at either end of a piece.
+    #[serde(rename = "tie")]
+    Tie,
+}
+
+/// The path of one yarn around its nails.
+pub struct Path {
+    /// How the yarn goes around each nail of the sequence.
+    pub turns: Vec<Turn>,
+    /// The straight pieces of yarn between the nails, from tangent point to tangent point.
+    pub segments: Vec<((f32, f32), (f32, f32))>,
+    /// The length of all segments and the arcs around the nails, in millimetres.
+    pub length: f32,
+    /// The length of yarn laid up to and around each nail of the sequence.
+    pub laid: Vec<f32>,
+}
+
+impl Turn {
+    /// The side of the yarn the nail is on, looking along it.
+    fn side(self) -> f32 {
+        match self {
+            Turn::Clockwise => 1.0,
+            Turn::CounterClockwise => -1.0,
+            Turn::Tie => 0.0,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Turn::Clockwise => "clockwise",
+            Turn::CounterClockwise => "counter-clockwise",
+            Turn::Tie => "tie",
+        }
+    }
+}
+
+/// Lay a yarn through nails of the given radius, at positions in millimetres.
+pub fn path(
+    points: &[(f32, f32)],
+    radius: f32,
+    sequence: &[PolygonPoint],
+    breaks: &[usize],
+) -> Path {
+    let at = |PolygonPoint(idx): PolygonPoint| points[idx];
+
+    let turns: Vec<Turn> = (0..sequence.len())
+        .map(|idx| {
+            if idx == 0 || idx + 1 == sequence.len() || breaks.contains(&idx) {
+                return Turn::Tie;
+            }
+
+            let (a, b, c) = (at(sequence[idx - 1]), at(sequence[idx]), at(sequence[idx + 1]));
+            let bend = cross(sub(b, a), sub(c, b));
+
+            // Straight through or back the same way, either side will do.
+            if bend >= 0.0 {
+                Turn::Clockwise
+            } else {
+                Turn::CounterClockwise
+            }
+        })
+        .collect();
+
+    let mut segments = vec![];
+    let mut directions = vec![];
+    let mut straights = vec![0.0; sequence.len()];
+    let mut arcs = vec![0.0; sequence.len()];
+
+    for (step, pair) in sequence.windows(2).enumerate() {
+        let (from, to) = (at(pair[0]), at(pair[1]));
+        let (s_from, s_to) = (turns[step].side(), turns[step + 1].side());
+
+        let delta = sub(to, from);
+        let distance = dot(delta, delta).sqrt();
+
+        if distance <= f32::EPSILON {
+            continue;
+        }
+
+        let d = scale(delta, 1.0 / distance);
+        // Offset between the tangent points across the yarn, non-zero if crossing over.
+        let mut k = (s_to - s_from) * radius;
+        if k.abs() >= distance {
+            k = 0.0;
+        }
+
+        let straight = (distance * distance - k * k).sqrt();
+        let u = scale(sub(scale(d, straight), scale(perp(d), k)), 1.0 / distance);
+
+        let start = sub(from, scale(perp(u), s_from * radius));
+        let end = sub(to, scale(perp(u), s_to * radius));
+
+        segments.push((start, end));
+        directions.push((step, u));
+        straights[step + 1] = straight;
+    }
+
+    // The arc around each wrapped nail, from the incoming to the outgoing direction.
+    for pair in directions.windows(2) {
+        let [(step, u_in), (next, u_out)] = [pair[0], pair[1]];
+        if next != step + 1 {
+            continue;
+        }
+
+        let side = turns[next].side();
+        let mut angle = (side * cross(u_in, u_out)).atan2(dot(u_in, u_out));
+        if angle < 0.0 {
+            angle += 2.0 * std::f32::consts::PI;
+        }
+
+        arcs[next] = angle * radius;
+    }
+
+    let laid: Vec<f32> = straights
+        .iter()
+        .zip(&arcs)
+        .scan(0.0, |total, (straight, arc)| {
+            *total += straight + arc;
+            Some(*total)
+        })
+        .collect();
+
+    Path {
+        turns,
+        segments,
+        length: laid.last().copied().unwrap_or(0.0),
+        laid,
+    }
+}
+
+/// Lay a yarn through the nails of a window, at positions in the coordinates of the definition.
+pub fn on_board(board: &Board, points: &[(f32, f32)], seq: &Sequence) -> Path {
+    let points: Vec<_> = points.iter().map(|&point| board.to_mm(point)).collect();
+    path(&points, board.nail_diameter_mm / 2.0, &seq.sequence, &seq.breaks)
+}
+
+fn sub(a: (f32, f32), b: (f32, f32)) -> (f32, f32) {
+    (a.0 - b.0, a.1 - b.1)
+}
+
+fn scale(a: (f32, f32), f: f32) -> (f32, f32) {
+    (a.0 * f, a.1 * f)
+}
+
+fn dot(a: (f32, f32), b: (f32, f32)) -> f32 {
+    a.0 * b.0 + a.1 * b.1
+}
+
+fn cross(a: (f32, f32), b: (f32, f32)) -> f32 {
+    a.0 * b.1 - a.1 * b.0
+}
+
+/// Rotated by a quarter turn, onto the side of a clockwise wrapped nail.
+fn perp(a: (f32, f32)) -> (f32, f32) {
+    (-a.1, a.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAILS: [(f32, f32); 3] = [(0.0, 0.0), (10.0, 0.0), (10.0, 10.0)];
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn tied_ends_leave_from_the_center() {
+        let path = path(&NAILS, 1.0, &[PolygonPoint(0), PolygonPoint(1)], &[]);
+
+        assert_eq!(path.turns, [Turn::Tie, Turn::Tie]);
+        assert_eq!(path.segments, [((0.0, 0.0), (10.0, 0.0))]);
+        assert!(close(path.length, 10.0));
+    }
+
+    #[test]
+    fn wrapped_nail_is_touched_by_both_threads() {
+        let sequence = [PolygonPoint(0), PolygonPoint(1), PolygonPoint(2)];
+        let path = path(&NAILS, 1.0, &sequence, &[]);
+        let nail = NAILS[1];
+
+        assert_eq!(path.turns[1], Turn::Clockwise);
+
+        let [into, out_of] = [path.segments[0], path.segments[1]];
+
+        // Each thread meets the nail at its rim, at a right angle to the radius there.
+        for (at, (from, to)) in [(into.1, into), (out_of.0, out_of)] {
+            let radius = sub(at, nail);
+            assert!(close(dot(radius, radius).sqrt(), 1.0));
+            assert!(close(dot(radius, sub(to, from)), 0.0));
+        }
+    }
+
+    #[test]
+    fn length_includes_the_arc_around_a_nail() {
+        let sequence = [PolygonPoint(0), PolygonPoint(1), PolygonPoint(0)];
+        let path = path(&NAILS, 1.0, &sequence, &[]);
+
+        // Around the far side of the nail and back, between the tangents from the other center.
+        let arc = std::f32::consts::PI + 2.0 * (0.1f32).asin();
+        assert!(close(path.length, 2.0 * 99.0f32.sqrt() + arc));
+        assert!(close(path.laid[1], 99.0f32.sqrt() + arc));
+    }
+
+    #[test]
+    fn breaks_tie_the_yarn() {
+        let sequence = [PolygonPoint(0), PolygonPoint(1), PolygonPoint(2)];
+        let path = path(&NAILS, 1.0, &sequence, &[1]);
+
+        assert_eq!(path.turns, [Turn::Tie; 3]);
+        assert!(close(path.length, 20.0));
+    }
+}